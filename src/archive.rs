@@ -1,7 +1,11 @@
-use crate::keystore::{BlockId, KeyStore, ArchiveId, EncryptedArchiveName, EncryptedArchiveMetadata};
+use crate::keystore::{BlockId, KeyStore, ArchiveId, EncryptedArchiveName, EncryptedArchiveMetadata, EncryptedArchiveBlocklist};
+use crate::backend::Backend;
+use crate::chunker::{self, ChunkingStrategy};
+use crate::compression::Compression;
 use lzma;
 use crate::error::*;
 use serde_derive::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
 
 
 #[derive(Serialize, Deserialize, PartialEq, Clone)]
@@ -19,17 +23,77 @@ pub struct File {
 	/// Modification time (combine with mtime_nsec)
 	pub mtime: i64,
 	pub mtime_nsec: i64,
+	/// Access time (combine with atime_nsec).  Missing for archives created by older versions of preserve.
+	#[serde(default)]
+	pub atime: Option<i64>,
+	#[serde(default)]
+	pub atime_nsec: i64,
 	/// User id
 	pub uid: u32,
 	/// Group id
 	pub gid: u32,
 	/// File size
 	pub size: u64,
-	/// Data blocks (list of block ids)
+	/// Data blocks (list of block ids).  These hold the file's data with the holes left out.
 	pub blocks: Vec<BlockId>,
+	/// Holes in a sparse file, sorted by offset
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub holes: Vec<Hole>,
+	/// Extended attributes (including POSIX ACLs and file capabilities), by name
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub xattrs: BTreeMap<String, Vec<u8>>,
+	/// If specified, this File is a FIFO or device node rather than a regular file (is_dir is false and symlink is None)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub special: Option<SpecialFile>,
 }
 
 
+/// A region of a sparse file that reads as zeros and isn't stored.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub struct Hole {
+	pub offset: u64,
+	pub length: u64,
+}
+
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub enum SpecialFile {
+	Fifo,
+	BlockDevice { major: u32, minor: u32 },
+	CharDevice { major: u32, minor: u32 },
+}
+
+impl SpecialFile {
+	/// Split a device number (st_rdev) into major and minor numbers, using glibc's encoding.
+	pub fn split_rdev(rdev: u64) -> (u32, u32) {
+		let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0x0000_0fff);
+		let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0x0000_00ff);
+
+		(major as u32, minor as u32)
+	}
+
+	/// The device number (st_rdev) for mknod.  0 for FIFOs.
+	pub fn rdev(&self) -> u64 {
+		match *self {
+			SpecialFile::Fifo => 0,
+			SpecialFile::BlockDevice { major, minor } | SpecialFile::CharDevice { major, minor } => {
+				let (major, minor) = (major as u64, minor as u64);
+
+				((major & 0xffff_f000) << 32) | ((major & 0x0000_0fff) << 8) | ((minor & 0xffff_ff00) << 12) | (minor & 0x0000_00ff)
+			},
+		}
+	}
+}
+
+
+/// The current archive format version.
+/// Version 2 added the creation metadata (timestamps, host, options, stats).  Version 1 archives are still readable;
+/// the new fields are simply None.
+/// Version 3 added special files (File::special), which older versions would restore as empty regular files.
+/// Version 4 added holes in sparse files (File::holes), which older versions would restore incorrectly.
+pub const ARCHIVE_VERSION: u32 = 0x00000004;
+
+
 /// An archive has some metadata, but it is primarily just a list of files.
 /// While the original filesystem was likely a file tree, we squash it to a simple list, since dealing
 /// with it as a tree would require lots of extra, nasty code.
@@ -39,25 +103,148 @@ pub struct File {
 pub struct Archive {
 	pub version: u32,
 	pub name: String,
+	/// When the backup was started (seconds since the Unix epoch).  Missing for archives created by older versions of preserve.
+	#[serde(default)]
+	pub created: Option<i64>,
+	/// When the backup finished reading files (seconds since the Unix epoch)
+	#[serde(default)]
+	pub finished: Option<i64>,
+	/// Hostname of the machine the backup was made on
+	#[serde(default)]
+	pub hostname: Option<String>,
+	/// The user that ran the backup
+	#[serde(default)]
+	pub username: Option<String>,
+	/// Version of preserve that created the archive
+	#[serde(default)]
+	pub preserve_version: Option<String>,
+	/// Options the backup was created with
+	#[serde(default)]
+	pub options: Option<CreateOptions>,
+	#[serde(default)]
+	pub stats: Option<ArchiveStats>,
+	/// Names of the users owning the files in the archive, by uid.  Used to map owners by name when restoring on another machine.
+	#[serde(default)]
+	pub user_names: BTreeMap<u32, String>,
+	/// Names of the groups owning the files in the archive, by gid
+	#[serde(default)]
+	pub group_names: BTreeMap<u32, String>,
 	pub original_path: String,
 	pub files: Vec<File>,
 }
 
 
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct CreateOptions {
+	pub dereference: bool,
+	pub one_file_system: bool,
+	pub excludes: Vec<String>,
+	/// Extended attribute namespaces that were backed up
+	#[serde(default)]
+	pub xattr_namespaces: Vec<String>,
+	/// How files were split into blocks.  Missing for archives created before chunking was configurable, which used chunker::LEGACY_STRATEGY.
+	#[serde(default)]
+	pub chunking: Option<ChunkingStrategy>,
+	/// Codec blocks were compressed with.  Older archives didn't compress blocks.
+	#[serde(default)]
+	pub compression: Compression,
+}
+
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ArchiveStats {
+	/// Number of entries in the archive (files, directories, symlinks)
+	pub file_count: u64,
+	/// Total size of all the files in the archive
+	pub total_size: u64,
+	/// Size of the blocks that did not already exist in the backend, and were uploaded while creating this archive
+	pub new_bytes: u64,
+}
+
+
+// Used to check an archive's version before trying to parse the rest of it
+#[derive(Deserialize)]
+struct ArchiveVersion {
+	version: u32,
+}
+
+
 impl Archive {
-	pub fn encrypt(self, keystore: &KeyStore) -> Result<(ArchiveId, EncryptedArchiveName, EncryptedArchiveMetadata)> {
+	pub fn encrypt(self, keystore: &KeyStore) -> Result<(ArchiveId, EncryptedArchiveName, EncryptedArchiveBlocklist, EncryptedArchiveMetadata)> {
 		let (archive_id, encrypted_name) = keystore.encrypt_archive_name(&self.name);
+		let encrypted_blocklist = keystore.encrypt_archive_blocklist(&archive_id, &self.blocklist());
 
 		let encoded = serde_json::to_vec(&self).expect("internal error");   // Serde shouldn't fail
 		let compressed = lzma::compress(&encoded, 9 | lzma::EXTREME_PRESET).expect("internal error");  // Compression shouldn't fail
 		let encrypted_archive = keystore.encrypt_archive_metadata(&archive_id, &compressed);
 
-		Ok((archive_id, encrypted_name, encrypted_archive))
+		Ok((archive_id, encrypted_name, encrypted_blocklist, encrypted_archive))
 	}
 
 	pub fn decrypt(archive_id: &ArchiveId, encrypted_archive: &EncryptedArchiveMetadata, keystore: &KeyStore) -> Result<Archive> {
 		let compressed = keystore.decrypt_archive_metadata(archive_id,  encrypted_archive)?;
 		let decompressed = lzma::decompress(&compressed).map_err(|_| Error::CorruptArchiveFailedDecompression)?;
+		let version: ArchiveVersion = serde_json::from_slice(&decompressed).map_err(|_| Error::CorruptArchiveBadJson)?;
+
+		if version.version == 0 || version.version > ARCHIVE_VERSION {
+			return Err(Error::UnsupportedArchiveVersion);
+		}
+
 		serde_json::from_slice(&decompressed).map_err(|_| Error::CorruptArchiveBadJson)
 	}
+
+	/// Fetch the archive with the given name from the backend and decrypt it.
+	pub fn fetch(name: &str, keystore: &KeyStore, backend: &mut dyn Backend) -> Result<Archive> {
+		let (archive_id, _) = keystore.encrypt_archive_name(name);
+		let encrypted_archive = backend.fetch_archive(&archive_id)?;
+
+		Archive::decrypt(&archive_id, &encrypted_archive, keystore)
+	}
+
+	/// How the files in this archive were split into blocks.
+	pub fn chunking(&self) -> ChunkingStrategy {
+		self.options.as_ref().and_then(|options| options.chunking).unwrap_or(chunker::LEGACY_STRATEGY)
+	}
+
+	/// Codec the blocks of this archive were compressed with.
+	pub fn compression(&self) -> Compression {
+		self.options.as_ref().map(|options| options.compression).unwrap_or_default()
+	}
+
+	/// The sorted, deduplicated list of every block referenced by this archive.
+	pub fn blocklist(&self) -> Vec<BlockId> {
+		let blocks: BTreeSet<&BlockId> = self.files.iter().flat_map(|file| file.blocks.iter()).collect();
+
+		blocks.into_iter().cloned().collect()
+	}
+
+	/// Authenticate the archive's blocklist and make sure it lists exactly the blocks this archive references.
+	pub fn verify_blocklist(&self, archive_id: &ArchiveId, encrypted_blocklist: &EncryptedArchiveBlocklist, keystore: &KeyStore) -> Result<()> {
+		let blocklist = keystore.decrypt_archive_blocklist(archive_id, encrypted_blocklist)?;
+
+		if blocklist != self.blocklist() {
+			return Err(Error::CorruptArchiveBlocklist);
+		}
+
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::SpecialFile;
+
+	#[test]
+	fn test_rdev_round_trip() {
+		// /dev/null, /dev/sda1 and something with large major and minor numbers
+		for &(major, minor) in &[(1, 3), (8, 1), (0x12345, 0x6789a)] {
+			let device = SpecialFile::CharDevice { major, minor };
+			assert_eq!(SpecialFile::split_rdev(device.rdev()), (major, minor));
+		}
+
+		assert_eq!(SpecialFile::CharDevice { major: 1, minor: 3 }.rdev(), 0x103);
+		assert_eq!(SpecialFile::split_rdev(0x803), (8, 3));
+		assert_eq!(SpecialFile::Fifo.rdev(), 0);
+	}
 }
@@ -0,0 +1,114 @@
+use crate::keystore::{ArchiveId, EncryptedArchiveName, KeyStore};
+use crate::error::*;
+use data_encoding::HEXLOWER_PERMISSIVE;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+
+/// A local, append-only record of every archive created, one line per archive: hex(ArchiveId) hex(EncryptedArchiveName) hex(MAC).
+/// Each MAC covers the previous one (see KeyStore::archive_log_mac), so without the keyfile nobody can add, change, remove or
+/// reorder entries, except by cutting entries off the end.
+/// Checking a backend against the log shows whether any of the logged archives have been removed since.
+pub struct ArchiveLog {
+	path: PathBuf,
+	entries: Vec<(ArchiveId, EncryptedArchiveName)>,
+	last_mac: Vec<u8>,
+}
+
+impl ArchiveLog {
+	/// Open the log at path, authenticating every entry.  A log that doesn't exist yet is empty.
+	pub fn open<P: AsRef<Path>>(path: P, keystore: &KeyStore) -> Result<ArchiveLog> {
+		let contents = match fs::read_to_string(path.as_ref()) {
+			Ok(contents) => contents,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+			Err(err) => return Err(err.into()),
+		};
+		let mut entries = Vec::new();
+		let mut last_mac = vec![0u8; 32];
+
+		if !contents.is_empty() && !contents.ends_with('\n') {
+			return Err(Error::CorruptArchiveLog);
+		}
+
+		for line in contents.lines() {
+			let fields: Vec<&str> = line.split(' ').collect();
+
+			if fields.len() != 3 {
+				return Err(Error::CorruptArchiveLog);
+			}
+
+			let archive_id = ArchiveId::from_str(fields[0]).map_err(|_| Error::CorruptArchiveLog)?;
+			let encrypted_name = EncryptedArchiveName(HEXLOWER_PERMISSIVE.decode(fields[1].as_bytes()).map_err(|_| Error::CorruptArchiveLog)?);
+			let mac = HEXLOWER_PERMISSIVE.decode(fields[2].as_bytes()).map_err(|_| Error::CorruptArchiveLog)?;
+			let expected_mac = keystore.archive_log_mac(&last_mac, &archive_id, &encrypted_name);
+
+			if !crypto::util::fixed_time_eq(&mac, &expected_mac) {
+				return Err(Error::CorruptArchiveLog);
+			}
+
+			entries.push((archive_id, encrypted_name));
+			last_mac = mac;
+		}
+
+		Ok(ArchiveLog {
+			path: path.as_ref().to_owned(),
+			entries,
+			last_mac,
+		})
+	}
+
+	pub fn entries(&self) -> &[(ArchiveId, EncryptedArchiveName)] {
+		&self.entries
+	}
+
+	/// Add an archive to the end of the log, and flush it to disk.
+	pub fn append(&mut self, keystore: &KeyStore, archive_id: &ArchiveId, encrypted_name: &EncryptedArchiveName) -> Result<()> {
+		let mac = keystore.archive_log_mac(&self.last_mac, archive_id, encrypted_name);
+		let line = format!("{} {} {}\n", archive_id.to_string(), HEXLOWER_PERMISSIVE.encode(&encrypted_name.0), HEXLOWER_PERMISSIVE.encode(&mac));
+
+		let mut file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+		file.write_all(line.as_bytes())?;
+		file.sync_all()?;
+
+		self.entries.push((*archive_id, EncryptedArchiveName(encrypted_name.0.clone())));
+		self.last_mac = mac;
+
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::ArchiveLog;
+	use crate::keystore::KeyStore;
+	use std::fs;
+
+	#[test]
+	fn test_archive_log() {
+		let dir = tempfile::Builder::new().prefix("preserve-test-").tempdir().unwrap();
+		let path = dir.path().join("archive.log");
+		let keystore = KeyStore::new();
+		let (id1, name1) = keystore.encrypt_archive_name("backup-1");
+		let (id2, name2) = keystore.encrypt_archive_name("backup-2");
+
+		let mut log = ArchiveLog::open(&path, &keystore).unwrap();
+		assert!(log.entries().is_empty());
+		log.append(&keystore, &id1, &name1).unwrap();
+		log.append(&keystore, &id2, &name2).unwrap();
+
+		let log = ArchiveLog::open(&path, &keystore).unwrap();
+		assert_eq!(log.entries().iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![id1, id2]);
+		assert_eq!(keystore.decrypt_archive_name(&log.entries()[1].0, &log.entries()[1].1).unwrap(), "backup-2");
+
+		// Only the right keyfile can authenticate the log
+		assert!(ArchiveLog::open(&path, &KeyStore::new()).is_err());
+
+		// Removing an entry breaks the chain
+		let contents = fs::read_to_string(&path).unwrap();
+		fs::write(&path, contents.lines().nth(1).unwrap().to_owned() + "\n").unwrap();
+		assert!(ArchiveLog::open(&path, &keystore).is_err());
+	}
+}
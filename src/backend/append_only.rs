@@ -0,0 +1,107 @@
+use crate::backend::Backend;
+use crate::keystore::{ArchiveId, EncryptedArchiveName, EncryptedArchiveMetadata, EncryptedArchiveBlocklist, EncryptedBlock, BlockId};
+use crate::error::*;
+use log::error;
+
+
+/// Wraps another backend, refusing anything that would delete or replace what's already stored.
+/// Used when the backend path has `?append-only=1`, so that a mistake (or something malicious) running with our credentials
+/// can't destroy existing backups through preserve.
+pub struct AppendOnlyBackend {
+	backend: Box<dyn Backend>,
+}
+
+impl AppendOnlyBackend {
+	pub fn new(backend: Box<dyn Backend>) -> AppendOnlyBackend {
+		AppendOnlyBackend {
+			backend,
+		}
+	}
+}
+
+impl Backend for AppendOnlyBackend {
+	fn block_exists(&mut self, id: &BlockId) -> Result<bool> {
+		self.backend.block_exists(id)
+	}
+
+	fn store_block(&mut self, id: &BlockId, data: &EncryptedBlock) -> Result<()> {
+		// A block's id authenticates its content, so an existing block already holds exactly this data
+		if self.backend.block_exists(id)? {
+			return Ok(());
+		}
+
+		self.backend.store_block(id, data)
+	}
+
+	fn fetch_block(&mut self, id: &BlockId) -> Result<EncryptedBlock> {
+		self.backend.fetch_block(id)
+	}
+
+	fn list_blocks(&mut self) -> Result<Vec<(BlockId, u64)>> {
+		self.backend.list_blocks()
+	}
+
+	fn delete_block(&mut self, _id: &BlockId) -> Result<()> {
+		Err(Error::AppendOnly)
+	}
+
+	fn store_archive(&mut self, id: &ArchiveId, name: &EncryptedArchiveName, blocklist: &EncryptedArchiveBlocklist, data: &EncryptedArchiveMetadata) -> Result<()> {
+		// Backends are supposed to refuse this themselves, but we don't rely on it
+		if self.backend.list_archives()?.iter().any(|(archive_id, _)| archive_id == id) {
+			error!("Refusing to replace archive {} in an append-only backend", id.to_string());
+			return Err(Error::ArchiveNameConflict);
+		}
+
+		self.backend.store_archive(id, name, blocklist, data)
+	}
+
+	fn fetch_archive(&mut self, id: &ArchiveId) -> Result<EncryptedArchiveMetadata> {
+		self.backend.fetch_archive(id)
+	}
+
+	fn fetch_archive_blocklist(&mut self, id: &ArchiveId) -> Result<Option<EncryptedArchiveBlocklist>> {
+		self.backend.fetch_archive_blocklist(id)
+	}
+
+	fn list_archives(&mut self) -> Result<Vec<(ArchiveId, EncryptedArchiveName)>> {
+		self.backend.list_archives()
+	}
+
+	fn delete_archive(&mut self, _id: &ArchiveId) -> Result<()> {
+		Err(Error::AppendOnly)
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.backend.flush()
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::AppendOnlyBackend;
+	use crate::backend::{Backend, FileBackend};
+	use crate::error::Error;
+	use crate::keystore::{ArchiveId, BlockId, EncryptedArchiveBlocklist, EncryptedArchiveMetadata, EncryptedArchiveName, EncryptedBlock};
+
+	#[test]
+	fn test_append_only() {
+		let dir = tempfile::Builder::new().prefix("preserve-test-").tempdir().unwrap();
+		let mut backend = AppendOnlyBackend::new(Box::new(FileBackend::new(dir.path())));
+		let block_id = BlockId::from_slice(&[1; 32]).unwrap();
+		let archive_id = ArchiveId::from_slice(&[2; 32]).unwrap();
+		let blocklist = || EncryptedArchiveBlocklist([&[0; 32][..], &block_id[..]].concat());
+
+		backend.store_block(&block_id, &EncryptedBlock(vec![1, 2, 3])).unwrap();
+		backend.store_block(&block_id, &EncryptedBlock(vec![4, 5, 6])).unwrap();
+		assert_eq!(backend.fetch_block(&block_id).unwrap().0, vec![1, 2, 3]);
+
+		backend.store_archive(&archive_id, &EncryptedArchiveName(vec![1]), &blocklist(), &EncryptedArchiveMetadata(vec![1])).unwrap();
+		assert!(matches!(backend.store_archive(&archive_id, &EncryptedArchiveName(vec![2]), &blocklist(), &EncryptedArchiveMetadata(vec![2])), Err(Error::ArchiveNameConflict)));
+		assert_eq!(backend.fetch_archive(&archive_id).unwrap().0, vec![1]);
+
+		assert!(matches!(backend.delete_archive(&archive_id), Err(Error::AppendOnly)));
+		assert!(matches!(backend.delete_block(&block_id), Err(Error::AppendOnly)));
+		assert_eq!(backend.list_archives().unwrap().len(), 1);
+	}
+}
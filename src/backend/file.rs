@@ -1,24 +1,267 @@
 use crate::backend::Backend;
-use crate::keystore::{ArchiveId, EncryptedArchiveName, EncryptedArchiveMetadata, EncryptedBlock, BlockId};
+use crate::backend::index::BackendIndex;
+use crate::keystore::{ArchiveId, EncryptedArchiveName, EncryptedArchiveMetadata, EncryptedArchiveBlocklist, EncryptedBlock, BlockId};
 use std::path::{Path, PathBuf};
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::fs::{self, OpenOptions};
 use rand::rngs::OsRng;
-use rand::Rng;
+use rand::{Rng, RngCore};
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::str::FromStr;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use data_encoding::HEXLOWER_PERMISSIVE;
+use log::warn;
 use crate::error::*;
 
 
+/// Blocks are collected in memory and written out as a pack once they add up to this many bytes.
+const PACK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Each entry in a pack's index is BlockId || le64(offset) || le64(length)
+const INDEX_ENTRY_SIZE: usize = 32 + 8 + 8;
+
+
+/// Blocks are stored in packs: packs/xx/<pack>.pack holds many encrypted blocks back to back, and packs/xx/<pack>.index
+/// says where each block is.  Packs are never modified; the index is written after its pack, so a pack without an index
+/// (from an interrupted write) is ignored.  Removing blocks only updates the in-memory index; flushing the backend then rewrites
+/// each affected pack's index once, and moves the rest of a pack's blocks to a new pack once most of it is unused.
+/// Backends created by older versions stored every block in its own file, blocks/xx/yy/<id>.  Those are still read and removed.
 pub struct FileBackend {
 	backup_dir: PathBuf,
+	/// Where every packed block is.  Loaded the first time it's needed.
+	index: Option<HashMap<BlockId, PackEntry>>,
+	/// Packs holding another copy of a block, left behind by an interrupted repack.  Loaded with the index.
+	stale_copies: HashMap<BlockId, Vec<String>>,
+	/// Blocks waiting to be written as a pack
+	pending: Vec<u8>,
+	pending_index: HashMap<BlockId, (u64, u64)>,
+	/// Packs that blocks were removed from since the last flush
+	dirty_packs: HashSet<String>,
+	/// Opened the first time it's needed
+	backend_index: Option<BackendIndex>,
+}
+
+#[derive(Clone)]
+struct PackEntry {
+	pack: String,
+	offset: u64,
+	length: u64,
 }
 
 impl FileBackend {
 	pub fn new<P: AsRef<Path>>(backup_dir: P) -> FileBackend {
 		FileBackend {
 			backup_dir: backup_dir.as_ref().to_path_buf(),
+			index: None,
+			stale_copies: HashMap::new(),
+			pending: Vec::new(),
+			pending_index: HashMap::new(),
+			dirty_packs: HashSet::new(),
+			backend_index: None,
+		}
+	}
+
+	/// Open the backend's index, rebuilding it from the archives' blocklists if it doesn't exist.
+	fn open_backend_index(&self) -> Result<BackendIndex> {
+		fs::create_dir_all(&self.backup_dir).unwrap_or(());
+
+		BackendIndex::open(self.backup_dir.join("index.sqlite"), || {
+			let mut archives = Vec::new();
+			let archives_dir = self.backup_dir.join("archives");
+
+			if !archives_dir.exists() {
+				return Ok(archives);
+			}
+
+			for entry in fs::read_dir(archives_dir)? {
+				let path = entry?.path();
+
+				if path.extension() != Some("name".as_ref()) {
+					continue;
+				}
+
+				let filename_str = path.file_stem().and_then(|stem| stem.to_str()).ok_or(Error::InvalidArchiveId)?;
+				let archive_id = ArchiveId::from_str(filename_str).map_err(|_| Error::InvalidArchiveId)?;
+				let blocklist_path = path.with_extension("blocklist");
+				let blocks = if blocklist_path.exists() {
+					Some(EncryptedArchiveBlocklist(fs::read(blocklist_path)?).unauthenticated_block_ids()?)
+				} else {
+					None
+				};
+
+				archives.push((archive_id, blocks));
+			}
+
+			Ok(archives)
+		})
+	}
+
+	/// Call f with the backend's index.  f also gets the backend, since the index's operations wrap writes to it.
+	fn with_backend_index<T, F: FnOnce(&mut FileBackend, &mut BackendIndex) -> Result<T>>(&mut self, f: F) -> Result<T> {
+		let mut backend_index = match self.backend_index.take() {
+			Some(backend_index) => backend_index,
+			None => self.open_backend_index()?,
+		};
+
+		let result = f(self, &mut backend_index);
+		self.backend_index = Some(backend_index);
+
+		result
+	}
+
+	fn loose_block_path(&self, id: &BlockId) -> PathBuf {
+		let block_id = id.to_string();
+		let dir1 = &block_id[0..2];
+		let dir2 = &block_id[2..4];
+
+		self.backup_dir.join("blocks").join(dir1).join(dir2).join(&block_id)
+	}
+
+	fn pack_path(&self, pack: &str, extension: &str) -> PathBuf {
+		self.backup_dir.join("packs").join(&pack[0..2]).join(format!("{}.{}", pack, extension))
+	}
+
+	fn index(&mut self) -> Result<&mut HashMap<BlockId, PackEntry>> {
+		if self.index.is_none() {
+			self.load_index()?;
+		}
+
+		Ok(self.index.as_mut().expect("internal error"))
+	}
+
+	/// Read every pack index into self.index, noting any block found in more than one pack
+	fn load_index(&mut self) -> Result<()> {
+		let mut index = HashMap::new();
+		let packs_dir = self.backup_dir.join("packs");
+
+		if !packs_dir.exists() {
+			self.index = Some(index);
+			return Ok(());
+		}
+
+		for dir in fs::read_dir(packs_dir)? {
+			for entry in fs::read_dir(dir?.path())? {
+				let path = entry?.path();
+
+				if path.extension() != Some("index".as_ref()) {
+					continue;
+				}
+
+				let pack = path.file_stem().and_then(|stem| stem.to_str()).ok_or(Error::CorruptPackIndex)?.to_owned();
+
+				// A block can be in two packs if repacking was interrupted.  Either copy is fine to read, but removing the block
+				// has to remove both.
+				for (block_id, offset, length) in decode_index(&fs::read(&path)?)? {
+					if let Some(other) = index.insert(block_id, PackEntry { pack: pack.clone(), offset, length }) {
+						self.stale_copies.entry(block_id).or_default().push(other.pack);
+					}
+				}
+			}
+		}
+
+		self.index = Some(index);
+		Ok(())
+	}
+
+	/// Write the pending blocks out as a new pack.
+	fn flush_pack(&mut self) -> Result<()> {
+		if self.pending_index.is_empty() {
+			return Ok(());
+		}
+
+		let pending = std::mem::take(&mut self.pending);
+		let pending_index: Vec<(BlockId, u64, u64)> = self.pending_index.drain().map(|(block_id, (offset, length))| (block_id, offset, length)).collect();
+		let pack = self.write_pack(&pending, &pending_index)?;
+
+		let index = self.index()?;
+		for (block_id, offset, length) in pending_index {
+			index.insert(block_id, PackEntry { pack: pack.clone(), offset, length });
+		}
+
+		Ok(())
+	}
+
+	/// Store a new pack and its index, returning the pack's name.
+	fn write_pack(&self, data: &[u8], entries: &[(BlockId, u64, u64)]) -> Result<String> {
+		let pack = {
+			let mut bytes = [0u8; 32];
+			OsRng.fill_bytes(&mut bytes);
+			HEXLOWER_PERMISSIVE.encode(&bytes)
+		};
+
+		fs::create_dir_all(self.backup_dir.join("packs").join(&pack[0..2])).unwrap_or(());
+		self.safely_write_file(self.pack_path(&pack, "pack"), data)?;
+		self.safely_write_file(self.pack_path(&pack, "index"), &encode_index(entries))?;
+
+		Ok(pack)
+	}
+
+	fn read_from_pack(&self, entry: &PackEntry) -> Result<Vec<u8>> {
+		let mut file = fs::File::open(self.pack_path(&entry.pack, "pack"))?;
+		let mut data = vec![0u8; usize::try_from(entry.length).map_err(|_| Error::CorruptPackIndex)?];
+
+		file.seek(SeekFrom::Start(entry.offset))?;
+		file.read_exact(&mut data)?;
+
+		Ok(data)
+	}
+
+	/// Called after blocks were removed from the dirty packs.  Rewrites each one's index, or, if most of the pack is now unused,
+	/// moves the remaining blocks into a new pack.
+	fn compact_packs(&mut self) -> Result<()> {
+		let dirty_packs = std::mem::take(&mut self.dirty_packs);
+		let mut pack_entries: HashMap<String, Vec<(BlockId, PackEntry)>> = dirty_packs.iter().map(|pack| (pack.clone(), Vec::new())).collect();
+
+		for (block_id, entry) in self.index()?.iter() {
+			if let Some(entries) = pack_entries.get_mut(&entry.pack) {
+				entries.push((*block_id, entry.clone()));
+			}
+		}
+
+		// If this fails, the on-disk indexes still list some removed blocks, so they'll show up for the next gc
+		for (pack, entries) in pack_entries {
+			self.compact_pack(&pack, &entries)?;
+		}
+
+		Ok(())
+	}
+
+	/// entries are the blocks still in pack.
+	fn compact_pack(&mut self, pack: &str, entries: &[(BlockId, PackEntry)]) -> Result<()> {
+		let pack_path = self.pack_path(pack, "pack");
+		let index_path = self.pack_path(pack, "index");
+		let used: u64 = entries.iter().map(|(_, entry)| entry.length).sum();
+		let pack_size = pack_path.metadata()?.len();
+
+		if entries.is_empty() {
+			// The index goes first, so the pack is never listed without existing
+			fs::remove_file(index_path)?;
+			fs::remove_file(pack_path)?;
+		} else if used < pack_size / 2 {
+			let mut data = Vec::new();
+			let mut new_entries = Vec::new();
+
+			for (block_id, entry) in entries {
+				new_entries.push((*block_id, data.len() as u64, entry.length));
+				data.extend_from_slice(&self.read_from_pack(entry)?);
+			}
+
+			// Until the old index is removed the blocks are in both packs, which is harmless
+			let new_pack = self.write_pack(&data, &new_entries)?;
+			fs::remove_file(index_path)?;
+			fs::remove_file(pack_path)?;
+
+			let index = self.index()?;
+			for (block_id, offset, length) in new_entries {
+				index.insert(block_id, PackEntry { pack: new_pack.clone(), offset, length });
+			}
+		} else {
+			let entries: Vec<(BlockId, u64, u64)> = entries.iter().map(|(block_id, entry)| (*block_id, entry.offset, entry.length)).collect();
+			self.safely_write_file(index_path, &encode_index(&entries))?;
 		}
+
+		Ok(())
 	}
 
 	fn safely_write_file<P: AsRef<Path>>(&self, destination: P, data: &[u8]) -> Result<()> {
@@ -60,40 +303,38 @@ impl FileBackend {
 
 impl Backend for FileBackend {
 	fn block_exists(&mut self, id: &BlockId) -> Result<bool> {
-		let block_id = id.to_string();
-		let dir1 = &block_id[0..2];
-		let dir2 = &block_id[2..4];
-
-		let path = self.backup_dir.join("blocks").join(dir1).join(dir2).join(&block_id);
+		if self.pending_index.contains_key(id) || self.index()?.contains_key(id) {
+			return Ok(true);
+		}
 
-		Ok(path.exists())
+		Ok(self.loose_block_path(id).exists())
 	}
 
 	fn store_block(&mut self, id: &BlockId, data: &EncryptedBlock) -> Result<()> {
-		let block_id = id.to_string();
-		let dir1 = &block_id[0..2];
-		let dir2 = &block_id[2..4];
+		if self.block_exists(id)? {
+			return Ok(());
+		}
 
-		let path = {
-			let path = self.backup_dir.join("blocks").join(dir1).join(dir2);
-			fs::create_dir_all(&path).unwrap_or(());
-			path.join(&block_id)
-		};
+		self.pending_index.insert(*id, (self.pending.len() as u64, data.0.len() as u64));
+		self.pending.extend_from_slice(&data.0);
 
-		if path.exists() {
-			return Ok(());
+		if self.pending.len() >= PACK_SIZE {
+			self.flush_pack()?;
 		}
 
-		self.safely_write_file(path, &data.0)
+		Ok(())
 	}
 
 	fn fetch_block(&mut self, id: &BlockId) -> Result<EncryptedBlock> {
-		let block_id = id.to_string();
-		let dir1 = &block_id[0..2];
-		let dir2 = &block_id[2..4];
+		if let Some(&(offset, length)) = self.pending_index.get(id) {
+			return Ok(EncryptedBlock(self.pending[offset as usize..(offset + length) as usize].to_vec()));
+		}
+
+		if let Some(entry) = self.index()?.get(id).cloned() {
+			return Ok(EncryptedBlock(self.read_from_pack(&entry)?));
+		}
 
-		let path = self.backup_dir.join("blocks").join(dir1).join(dir2).join(&block_id);
-		let mut file = fs::File::open(path)?;
+		let mut file = fs::File::open(self.loose_block_path(id))?;
 
 		let mut ciphertext = Vec::<u8>::new();
 
@@ -102,6 +343,59 @@ impl Backend for FileBackend {
 		Ok(EncryptedBlock(ciphertext))
 	}
 
+	fn list_blocks(&mut self) -> Result<Vec<(BlockId, u64)>> {
+		self.flush_pack()?;
+
+		let mut blocks: Vec<(BlockId, u64)> = self.index()?.iter().map(|(block_id, entry)| (*block_id, entry.length)).collect();
+		let blocks_dir = self.backup_dir.join("blocks");
+
+		if !blocks_dir.exists() {
+			return Ok(blocks);
+		}
+
+		// Loose blocks are stored as blocks/xx/yy/<id>
+		for dir1 in fs::read_dir(blocks_dir)? {
+			for dir2 in fs::read_dir(dir1?.path())? {
+				for entry in fs::read_dir(dir2?.path())? {
+					let entry = entry?;
+					let filename = entry.file_name();
+					let filename_str = filename.to_str().ok_or(Error::InvalidBlockId)?;
+					let block_id = BlockId::from_str(filename_str)?;
+
+					blocks.push((block_id, entry.metadata()?.len()));
+				}
+			}
+		}
+
+		Ok(blocks)
+	}
+
+	fn delete_block(&mut self, id: &BlockId) -> Result<()> {
+		self.flush_pack()?;
+
+		// Just in case an archive referencing the block was stored after the caller decided it was unused
+		if self.with_backend_index(|_, backend_index| backend_index.refcount(id))? > 0 {
+			return Err(Error::BlockReferenced);
+		}
+
+		let path = self.loose_block_path(id);
+
+		if path.exists() {
+			return Ok(fs::remove_file(path)?);
+		}
+
+		// The pack is compacted when the backend is flushed, so that removing many blocks rewrites each pack only once
+		match self.index()?.remove(id) {
+			Some(entry) => {
+				// Compacting a pack keeps only the blocks the index points at, so this drops the stale copies too
+				self.dirty_packs.extend(self.stale_copies.remove(id).unwrap_or_default());
+				self.dirty_packs.insert(entry.pack);
+				Ok(())
+			},
+			None => Err(Error::BlockNotFound),
+		}
+	}
+
 	fn fetch_archive(&mut self, id: &ArchiveId) -> Result<EncryptedArchiveMetadata> {
 		let path = self.backup_dir.join("archives").join(format!("{}.metadata", id.to_string()));
 
@@ -110,24 +404,51 @@ impl Backend for FileBackend {
 		Ok(EncryptedArchiveMetadata(data))
 	}
 
-	fn store_archive(&mut self, id: &ArchiveId, name: &EncryptedArchiveName, data: &EncryptedArchiveMetadata) -> Result<()> {
+	fn fetch_archive_blocklist(&mut self, id: &ArchiveId) -> Result<Option<EncryptedArchiveBlocklist>> {
+		let path = self.backup_dir.join("archives").join(format!("{}.blocklist", id.to_string()));
+
+		if !path.exists() {
+			return Ok(None);
+		}
+
+		let data = fs::read(path)?;
+
+		Ok(Some(EncryptedArchiveBlocklist(data)))
+	}
+
+	fn store_archive(&mut self, id: &ArchiveId, name: &EncryptedArchiveName, blocklist: &EncryptedArchiveBlocklist, data: &EncryptedArchiveMetadata) -> Result<()> {
+		// The archive's blocks have to be stored before the archive is
+		self.flush_pack()?;
+
 		let name_path = self.backup_dir.join("archives").join(format!("{}.name", id.to_string()));
+		let blocklist_path = self.backup_dir.join("archives").join(format!("{}.blocklist", id.to_string()));
 		let metadata_path = self.backup_dir.join("archives").join(format!("{}.metadata", id.to_string()));
 		fs::create_dir_all(&self.backup_dir.join("archives")).unwrap_or(());
 
-		// TODO: Right now there is a race condition here.  This will be fixed in the future when we add a SQLite database for managing refcounts and other atomic things.
-		if name_path.exists() {
-			return Err(Error::ArchiveNameConflict);
-		}
+		// The index reserves the name atomically.  The files are still checked, in case they were written by an older version of preserve.
+		let blocks = blocklist.unauthenticated_block_ids()?;
+
+		self.with_backend_index(|backend, backend_index| backend_index.add_archive(id, &blocks, || {
+			if name_path.exists() {
+				return Err(Error::ArchiveNameConflict);
+			}
 
-		self.safely_write_file(name_path, &name.0)?;
-		self.safely_write_file(metadata_path, &data.0)
+			// The name goes last, so an interrupted store never leaves a listed archive that can't be fetched.
+			backend.safely_write_file(blocklist_path, &blocklist.0)?;
+			backend.safely_write_file(metadata_path, &data.0)?;
+			backend.safely_write_file(name_path, &name.0)
+		}))
 	}
 
 	fn list_archives(&mut self) -> Result<Vec<(ArchiveId, EncryptedArchiveName)>> {
 		let mut archives = Vec::new();
+		let archives_dir = self.backup_dir.join("archives");
 
-		for entry in fs::read_dir(self.backup_dir.join("archives"))? {
+		if !archives_dir.exists() {
+			return Ok(archives);
+		}
+
+		for entry in fs::read_dir(archives_dir)? {
 			let path = entry?.path();
 
 			let extension = path.extension().ok_or(Error::InvalidArchiveId)?;
@@ -146,4 +467,152 @@ impl Backend for FileBackend {
 
 		Ok(archives)
 	}
+
+	fn delete_archive(&mut self, id: &ArchiveId) -> Result<()> {
+		let name_path = self.backup_dir.join("archives").join(format!("{}.name", id.to_string()));
+		let blocklist_path = self.backup_dir.join("archives").join(format!("{}.blocklist", id.to_string()));
+		let metadata_path = self.backup_dir.join("archives").join(format!("{}.metadata", id.to_string()));
+
+		if !name_path.exists() {
+			return Err(Error::ArchiveNotFound);
+		}
+
+		let blocks = if blocklist_path.exists() {
+			EncryptedArchiveBlocklist(fs::read(&blocklist_path)?).unauthenticated_block_ids()?
+		} else {
+			Vec::new()
+		};
+
+		self.with_backend_index(|_, backend_index| backend_index.remove_archive(id, &blocks, || {
+			// The name goes first, so that an interrupted delete never leaves a listed archive that can't be fetched.
+			fs::remove_file(name_path)?;
+
+			if blocklist_path.exists() {
+				fs::remove_file(blocklist_path)?;
+			}
+
+			Ok(fs::remove_file(metadata_path)?)
+		}))
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.flush_pack()?;
+		self.compact_packs()
+	}
+}
+
+impl Drop for FileBackend {
+	fn drop(&mut self) {
+		if let Err(err) = self.flush_pack() {
+			warn!("Unable to write {} blocks to the backend: {}", self.pending_index.len(), err);
+		}
+
+		if let Err(err) = self.compact_packs() {
+			warn!("Unable to compact the packs that blocks were removed from: {}", err);
+		}
+	}
+}
+
+
+fn encode_index(entries: &[(BlockId, u64, u64)]) -> Vec<u8> {
+	let mut data = Vec::with_capacity(entries.len() * INDEX_ENTRY_SIZE);
+
+	for (block_id, offset, length) in entries {
+		data.extend_from_slice(&block_id[..]);
+		data.extend_from_slice(&offset.to_le_bytes());
+		data.extend_from_slice(&length.to_le_bytes());
+	}
+
+	data
+}
+
+
+fn decode_index(data: &[u8]) -> Result<Vec<(BlockId, u64, u64)>> {
+	let entries = data.chunks_exact(INDEX_ENTRY_SIZE);
+
+	if !entries.remainder().is_empty() {
+		return Err(Error::CorruptPackIndex);
+	}
+
+	Ok(entries.map(|entry| {
+		let block_id = BlockId::from_slice(&entry[..32]).expect("internal error");
+		let offset = u64::from_le_bytes(<[u8; 8]>::try_from(&entry[32..40]).expect("internal error"));
+		let length = u64::from_le_bytes(<[u8; 8]>::try_from(&entry[40..48]).expect("internal error"));
+
+		(block_id, offset, length)
+	}).collect())
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::{FileBackend, PACK_SIZE};
+	use crate::backend::Backend;
+	use crate::keystore::{BlockId, EncryptedBlock};
+
+	fn block(i: u8, size: usize) -> (BlockId, EncryptedBlock) {
+		(BlockId::from_slice(&[i; 32]).unwrap(), EncryptedBlock(vec![i; size]))
+	}
+
+	#[test]
+	fn test_packs() {
+		let dir = tempfile::Builder::new().prefix("preserve-test-").tempdir().unwrap();
+		let blocks: Vec<(BlockId, EncryptedBlock)> = (0..8).map(|i| block(i, PACK_SIZE / 4 + i as usize)).collect();
+
+		{
+			let mut backend = FileBackend::new(dir.path());
+
+			for (block_id, data) in &blocks {
+				backend.store_block(block_id, data).unwrap();
+			}
+
+			// Still pending blocks are visible too
+			assert_eq!(backend.fetch_block(&blocks[7].0).unwrap().0, blocks[7].1 .0);
+		}
+
+		// Dropping the backend wrote the last pack
+		let mut backend = FileBackend::new(dir.path());
+		for (block_id, data) in &blocks {
+			assert!(backend.block_exists(block_id).unwrap());
+			assert_eq!(backend.fetch_block(block_id).unwrap().0, data.0);
+		}
+		assert_eq!(backend.list_blocks().unwrap().len(), 8);
+
+		// Removing most of a pack moves the rest to a new pack
+		for (block_id, _) in &blocks[..6] {
+			backend.delete_block(block_id).unwrap();
+		}
+		assert!(!backend.block_exists(&blocks[0].0).unwrap());
+		backend.flush().unwrap();
+
+		let mut backend = FileBackend::new(dir.path());
+		let mut listed = backend.list_blocks().unwrap();
+		listed.sort_by_key(|&(block_id, _)| block_id);
+		assert_eq!(listed, vec![(blocks[6].0, blocks[6].1 .0.len() as u64), (blocks[7].0, blocks[7].1 .0.len() as u64)]);
+		assert!(!backend.block_exists(&blocks[0].0).unwrap());
+		assert_eq!(backend.fetch_block(&blocks[6].0).unwrap().0, blocks[6].1 .0);
+		assert!(backend.delete_block(&blocks[0].0).is_err());
+	}
+
+	#[test]
+	fn test_interrupted_repack() {
+		let dir = tempfile::Builder::new().prefix("preserve-test-").tempdir().unwrap();
+		let (block_id, data) = block(0, 1024);
+
+		// Two packs with the same block, as if the old pack wasn't removed after repacking
+		{
+			let backend = FileBackend::new(dir.path());
+			backend.write_pack(&data.0, &[(block_id, 0, 1024)]).unwrap();
+			backend.write_pack(&data.0, &[(block_id, 0, 1024)]).unwrap();
+		}
+
+		let mut backend = FileBackend::new(dir.path());
+		assert_eq!(backend.list_blocks().unwrap(), vec![(block_id, 1024)]);
+		backend.delete_block(&block_id).unwrap();
+		backend.flush().unwrap();
+
+		let mut backend = FileBackend::new(dir.path());
+		assert!(!backend.block_exists(&block_id).unwrap());
+		assert!(backend.list_blocks().unwrap().is_empty());
+	}
 }
@@ -0,0 +1,201 @@
+use crate::backend::Backend;
+use crate::keystore::{ArchiveId, EncryptedArchiveName, EncryptedArchiveMetadata, EncryptedArchiveBlocklist, EncryptedBlock, BlockId};
+use data_encoding::BASE64;
+use serde_derive::{Deserialize, Serialize};
+use std::env;
+use std::io::Read;
+use url::Url;
+use crate::error::*;
+
+
+/// Environment variable holding the token for http(s) backends whose URL doesn't include one.
+pub const TOKEN_ENV: &str = "PRESERVE_SERVER_TOKEN";
+
+
+/// Body of PUT /archives/<id>.  All three parts are sent together so the server can store the archive in one go.
+#[derive(Serialize, Deserialize)]
+pub struct ArchiveUpload {
+	/// Base64
+	pub name: String,
+	/// Base64
+	pub blocklist: String,
+	/// Base64
+	pub metadata: String,
+}
+
+/// An entry in the response to GET /archives.  name is Base64.
+#[derive(Serialize, Deserialize)]
+pub struct ArchiveListing {
+	pub id: ArchiveId,
+	pub name: String,
+}
+
+
+/// A backend served by `preserve serve`, at http(s)://[:token@]host:port[/path].
+/// The server's API:
+///
+///   HEAD/GET/PUT/DELETE /blocks/<id>
+///   GET /blocks                          JSON list of [id, size]
+///   PUT/DELETE /archives/<id>            PUT takes an ArchiveUpload
+///   GET /archives                        JSON list of ArchiveListing
+///   GET /archives/<id>/metadata
+///   GET /archives/<id>/blocklist
+///   POST /flush                          Write out buffered blocks and finish removing deleted blocks from their packs
+///                                        (allowed by an append-only server, since nothing was deleted)
+///
+/// Every request carries "Authorization: Bearer <token>".  Missing things are 404, name conflicts and referenced
+/// blocks are 409, and deletes refused by an append-only server are 403.
+pub struct HttpBackend {
+	base_url: String,
+	authorization: String,
+}
+
+impl HttpBackend {
+	pub fn new(url: &Url) -> Result<HttpBackend> {
+		let token = match url.password() {
+			Some(password) => password.to_owned(),
+			None => env::var(TOKEN_ENV).map_err(|_| Error::BadBackendPath(format!("No token was given for the http backend.  Put it in the URL (http://:token@host/) or set {}", TOKEN_ENV)))?,
+		};
+
+		let mut base_url = url.clone();
+		base_url.set_username("").and_then(|_| base_url.set_password(None)).map_err(|_| Error::BadBackendPath("Invalid http backend path".to_string()))?;
+		base_url.set_query(None);
+
+		Ok(HttpBackend {
+			base_url: base_url.as_str().trim_end_matches('/').to_owned(),
+			authorization: format!("Bearer {}", token),
+		})
+	}
+
+	/// Returns the status and body of the response.  Authentication failures and server errors become errors.
+	fn request(&self, method: &str, path: &str, body: Option<&[u8]>) -> Result<(u16, Vec<u8>)> {
+		let request = ureq::request(method, &format!("{}{}", self.base_url, path)).set("Authorization", &self.authorization);
+		let result = match body {
+			Some(body) => request.send_bytes(body),
+			None => request.call(),
+		};
+
+		let response = match result {
+			Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+			Err(err) => return Err(Error::RemoteBackend(format!("Request to the server failed: {}", err))),
+		};
+
+		let status = response.status();
+		let mut data = Vec::new();
+		response.into_reader().read_to_end(&mut data)?;
+
+		match status {
+			401 => Err(Error::RemoteBackend("The server rejected our token".to_string())),
+			403 => Err(Error::AppendOnly),
+			status if status >= 400 && status != 404 && status != 409 => Err(Error::RemoteBackend(format!("The server returned {}: {}", status, String::from_utf8_lossy(&data)))),
+			status => Ok((status, data)),
+		}
+	}
+
+	/// For requests that change something.  Only a 2xx means it was done: a request sent to the wrong path (say, through a proxy
+	/// that doesn't strip a prefix) gets a 404 for every route, which must not pass for success.  Statuses in allowed are
+	/// returned for the caller to handle.
+	fn send(&self, method: &str, path: &str, body: Option<&[u8]>, allowed: &[u16]) -> Result<u16> {
+		match self.request(method, path, body)? {
+			(status, _) if (200..300).contains(&status) || allowed.contains(&status) => Ok(status),
+			(status, data) => Err(Error::RemoteBackend(format!("The server returned {} for {} {}: {}.  Is the backend path right?", status, method, path, String::from_utf8_lossy(&data)))),
+		}
+	}
+
+	fn get(&self, path: &str) -> Result<Option<Vec<u8>>> {
+		match self.request("GET", path, None)? {
+			(404, _) => Ok(None),
+			(_, data) => Ok(Some(data)),
+		}
+	}
+}
+
+impl Backend for HttpBackend {
+	fn block_exists(&mut self, id: &BlockId) -> Result<bool> {
+		Ok(self.request("HEAD", &format!("/blocks/{}", id.to_string()), None)?.0 != 404)
+	}
+
+	fn store_block(&mut self, id: &BlockId, data: &EncryptedBlock) -> Result<()> {
+		self.send("PUT", &format!("/blocks/{}", id.to_string()), Some(&data.0), &[])?;
+		Ok(())
+	}
+
+	fn fetch_block(&mut self, id: &BlockId) -> Result<EncryptedBlock> {
+		self.get(&format!("/blocks/{}", id.to_string()))?.map(EncryptedBlock).ok_or(Error::BlockNotFound)
+	}
+
+	fn list_blocks(&mut self) -> Result<Vec<(BlockId, u64)>> {
+		let data = self.get("/blocks")?.ok_or_else(|| Error::RemoteBackend("The server did not list its blocks".to_string()))?;
+
+		serde_json::from_slice(&data).map_err(|err| Error::RemoteBackend(format!("The server returned an invalid block list: {}", err)))
+	}
+
+	fn delete_block(&mut self, id: &BlockId) -> Result<()> {
+		match self.send("DELETE", &format!("/blocks/{}", id.to_string()), None, &[404, 409])? {
+			404 => Err(Error::BlockNotFound),
+			409 => Err(Error::BlockReferenced),
+			_ => Ok(()),
+		}
+	}
+
+	fn store_archive(&mut self, id: &ArchiveId, name: &EncryptedArchiveName, blocklist: &EncryptedArchiveBlocklist, data: &EncryptedArchiveMetadata) -> Result<()> {
+		let upload = ArchiveUpload {
+			name: BASE64.encode(&name.0),
+			blocklist: BASE64.encode(&blocklist.0),
+			metadata: BASE64.encode(&data.0),
+		};
+		let body = serde_json::to_vec(&upload).expect("internal error");
+
+		match self.send("PUT", &format!("/archives/{}", id.to_string()), Some(&body), &[409])? {
+			409 => Err(Error::ArchiveNameConflict),
+			_ => Ok(()),
+		}
+	}
+
+	fn fetch_archive(&mut self, id: &ArchiveId) -> Result<EncryptedArchiveMetadata> {
+		self.get(&format!("/archives/{}/metadata", id.to_string()))?.map(EncryptedArchiveMetadata).ok_or(Error::ArchiveNotFound)
+	}
+
+	fn fetch_archive_blocklist(&mut self, id: &ArchiveId) -> Result<Option<EncryptedArchiveBlocklist>> {
+		Ok(self.get(&format!("/archives/{}/blocklist", id.to_string()))?.map(EncryptedArchiveBlocklist))
+	}
+
+	fn list_archives(&mut self) -> Result<Vec<(ArchiveId, EncryptedArchiveName)>> {
+		let invalid = |err: String| Error::RemoteBackend(format!("The server returned an invalid archive list: {}", err));
+		let data = self.get("/archives")?.ok_or_else(|| invalid("not found".to_string()))?;
+		let listings: Vec<ArchiveListing> = serde_json::from_slice(&data).map_err(|err| invalid(err.to_string()))?;
+
+		listings.into_iter().map(|listing| {
+			let name = BASE64.decode(listing.name.as_bytes()).map_err(|err| invalid(err.to_string()))?;
+
+			Ok((listing.id, EncryptedArchiveName(name)))
+		}).collect()
+	}
+
+	fn delete_archive(&mut self, id: &ArchiveId) -> Result<()> {
+		match self.send("DELETE", &format!("/archives/{}", id.to_string()), None, &[404])? {
+			404 => Err(Error::ArchiveNotFound),
+			_ => Ok(()),
+		}
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.send("POST", "/flush", None, &[])?;
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::HttpBackend;
+	use url::Url;
+
+	#[test]
+	fn test_token_in_url() {
+		let backend = HttpBackend::new(&Url::parse("https://:secret-token@nas.local:8200/preserve/?unused=1").unwrap()).unwrap();
+
+		assert_eq!(backend.base_url, "https://nas.local:8200/preserve");
+		assert_eq!(backend.authorization, "Bearer secret-token");
+	}
+}
@@ -0,0 +1,144 @@
+use crate::keystore::{ArchiveId, BlockId};
+use crate::error::*;
+use rusqlite::{self, TransactionBehavior};
+use std::path::Path;
+use std::time::Duration;
+
+
+/// A SQLite database kept alongside the data in a backend.  It records which archives exist, so that names can be
+/// reserved atomically, and how many archives reference each block.
+/// Everything in it can be recomputed from the archives' blocklists, so if the database is lost it is simply rebuilt.
+pub struct BackendIndex {
+	db: rusqlite::Connection,
+}
+
+impl BackendIndex {
+	/// Open the index at path, creating it if needed.  A new index is filled in from the archives returned by
+	/// list_archives; archives created before blocklists were stored (None) are recorded without any blocks.
+	pub fn open<P: AsRef<Path>, F: FnOnce() -> Result<Vec<(ArchiveId, Option<Vec<BlockId>>)>>>(path: P, list_archives: F) -> Result<BackendIndex> {
+		let mut db = rusqlite::Connection::open(path)?;
+
+		// Other processes may be creating backups at the same time
+		db.busy_timeout(Duration::from_secs(60))?;
+
+		db.execute_batch("
+			CREATE TABLE IF NOT EXISTS archives (id TEXT PRIMARY KEY NOT NULL);
+			CREATE TABLE IF NOT EXISTS block_refcounts (id TEXT PRIMARY KEY NOT NULL, refcount INTEGER NOT NULL);
+			CREATE TABLE IF NOT EXISTS state (key TEXT PRIMARY KEY NOT NULL, value TEXT NOT NULL);
+		")?;
+
+		let built = db.query_row("SELECT value FROM state WHERE key='built'", rusqlite::NO_PARAMS, |_| Ok(())).is_ok();
+
+		if !built {
+			let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+			// Someone else may have built it while we waited for the lock
+			if tx.query_row("SELECT value FROM state WHERE key='built'", rusqlite::NO_PARAMS, |_| Ok(())).is_err() {
+				tx.execute("DELETE FROM archives", rusqlite::NO_PARAMS)?;
+				tx.execute("DELETE FROM block_refcounts", rusqlite::NO_PARAMS)?;
+
+				for (archive_id, blocks) in list_archives()? {
+					add_archive(&tx, &archive_id, &blocks.unwrap_or_default())?;
+				}
+
+				tx.execute("INSERT INTO state (key, value) VALUES ('built', '1')", rusqlite::NO_PARAMS)?;
+			}
+
+			tx.commit()?;
+		}
+
+		Ok(BackendIndex {
+			db,
+		})
+	}
+
+	/// Reserve archive_id and add references to blocks, then call write to store the archive.
+	/// Fails with ArchiveNameConflict if the archive already exists.  If write fails, nothing is recorded.
+	pub fn add_archive<F: FnOnce() -> Result<()>>(&mut self, archive_id: &ArchiveId, blocks: &[BlockId], write: F) -> Result<()> {
+		let tx = self.db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+		add_archive(&tx, archive_id, blocks)?;
+		write()?;
+
+		Ok(tx.commit()?)
+	}
+
+	/// Remove archive_id and its references to blocks, then call remove to delete the archive.
+	pub fn remove_archive<F: FnOnce() -> Result<()>>(&mut self, archive_id: &ArchiveId, blocks: &[BlockId], remove: F) -> Result<()> {
+		let tx = self.db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+		tx.execute("DELETE FROM archives WHERE id=?", &[&archive_id.to_string()])?;
+
+		for block_id in blocks {
+			tx.execute("UPDATE block_refcounts SET refcount = refcount - 1 WHERE id=?", &[&block_id.to_string()])?;
+		}
+
+		tx.execute("DELETE FROM block_refcounts WHERE refcount <= 0", rusqlite::NO_PARAMS)?;
+		remove()?;
+
+		Ok(tx.commit()?)
+	}
+
+	/// Number of archives referencing the block
+	pub fn refcount(&self, block_id: &BlockId) -> Result<u64> {
+		match self.db.query_row("SELECT refcount FROM block_refcounts WHERE id=?", &[&block_id.to_string()], |row| row.get::<_, i64>(0)) {
+			Ok(refcount) => Ok(refcount as u64),
+			Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
+			Err(err) => Err(err.into()),
+		}
+	}
+}
+
+
+fn add_archive(tx: &rusqlite::Transaction, archive_id: &ArchiveId, blocks: &[BlockId]) -> Result<()> {
+	match tx.execute("INSERT INTO archives (id) VALUES (?)", &[&archive_id.to_string()]) {
+		Ok(_) => (),
+		Err(rusqlite::Error::SqliteFailure(ref err, _)) if err.code == rusqlite::ErrorCode::ConstraintViolation => return Err(Error::ArchiveNameConflict),
+		Err(err) => return Err(err.into()),
+	}
+
+	// The blocklist is already deduplicated, so each archive adds at most one reference to a block
+	for block_id in blocks {
+		tx.execute("INSERT OR IGNORE INTO block_refcounts (id, refcount) VALUES (?, 0)", &[&block_id.to_string()])?;
+		tx.execute("UPDATE block_refcounts SET refcount = refcount + 1 WHERE id=?", &[&block_id.to_string()])?;
+	}
+
+	Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::BackendIndex;
+	use crate::error::Error;
+	use crate::keystore::{ArchiveId, BlockId};
+
+	#[test]
+	fn test_index() {
+		let dir = tempfile::Builder::new().prefix("preserve-test-").tempdir().unwrap();
+		let path = dir.path().join("index.sqlite");
+		let archive1 = ArchiveId::from_slice(&[1; 32]).unwrap();
+		let archive2 = ArchiveId::from_slice(&[2; 32]).unwrap();
+		let block1 = BlockId::from_slice(&[1; 32]).unwrap();
+		let block2 = BlockId::from_slice(&[2; 32]).unwrap();
+
+		// A new index is rebuilt from the existing archives
+		let mut index = BackendIndex::open(&path, || Ok(vec![(archive1, Some(vec![block1, block2]))])).unwrap();
+		assert_eq!(index.refcount(&block1).unwrap(), 1);
+
+		index.add_archive(&archive2, &[block2], || Ok(())).unwrap();
+		assert_eq!(index.refcount(&block2).unwrap(), 2);
+		assert!(matches!(index.add_archive(&archive2, &[block2], || Ok(())), Err(Error::ArchiveNameConflict)));
+
+		// Failed writes don't leave anything behind
+		let archive3 = ArchiveId::from_slice(&[3; 32]).unwrap();
+		assert!(index.add_archive(&archive3, &[block1], || Err(Error::BlockNotFound)).is_err());
+		assert_eq!(index.refcount(&block1).unwrap(), 1);
+
+		// An existing index is not rebuilt
+		let mut index = BackendIndex::open(&path, || panic!("rebuilt")).unwrap();
+		index.remove_archive(&archive1, &[block1, block2], || Ok(())).unwrap();
+		assert_eq!(index.refcount(&block1).unwrap(), 0);
+		assert_eq!(index.refcount(&block2).unwrap(), 1);
+	}
+}
@@ -0,0 +1,216 @@
+use crate::backend::Backend;
+use crate::keystore::{ArchiveId, EncryptedArchiveName, EncryptedArchiveMetadata, EncryptedArchiveBlocklist, EncryptedBlock, BlockId};
+use crate::error::*;
+use log::warn;
+
+
+/// What to do when one of the backends being mirrored to fails.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OnBackendFailure {
+	/// Carry on with the remaining backends
+	Warn,
+	/// Stop
+	Fail,
+}
+
+
+struct Mirror {
+	path: String,
+	/// None if the backend failed to open
+	backend: Option<Box<dyn Backend>>,
+	/// Why the backend failed, if it did
+	error: Option<String>,
+}
+
+
+/// Writes everything to several backends at once.  Blocks are only written to the backends that don't have them yet.
+/// Reads come from the first backend that hasn't failed.
+pub struct MirrorBackend {
+	mirrors: Vec<Mirror>,
+	on_failure: OnBackendFailure,
+}
+
+impl MirrorBackend {
+	pub fn new(on_failure: OnBackendFailure) -> MirrorBackend {
+		MirrorBackend {
+			mirrors: Vec::new(),
+			on_failure,
+		}
+	}
+
+	pub fn add(&mut self, path: &str, backend: Box<dyn Backend>) {
+		self.mirrors.push(Mirror {
+			path: path.to_owned(),
+			backend: Some(backend),
+			error: None,
+		});
+	}
+
+	/// Record a backend that couldn't be opened, so it shows up in the report
+	pub fn add_failed(&mut self, path: &str, err: &Error) {
+		self.mirrors.push(Mirror {
+			path: path.to_owned(),
+			backend: None,
+			error: Some(err.to_string()),
+		});
+	}
+
+	/// Each backend's path, and why it failed if it did.
+	pub fn report(&self) -> Vec<(&str, Option<&str>)> {
+		self.mirrors.iter().map(|mirror| (&mirror.path[..], mirror.error.as_ref().map(|err| &err[..]))).collect()
+	}
+
+	/// Call f with every backend that hasn't failed.  If f fails, either the whole operation fails or that backend is
+	/// dropped, depending on on_failure.  Name conflicts always fail, since they aren't a problem with the backend.
+	fn for_each<F: FnMut(&mut dyn Backend) -> Result<()>>(&mut self, mut f: F) -> Result<()> {
+		let on_failure = self.on_failure;
+
+		for mirror in &mut self.mirrors {
+			let backend = match (&mut mirror.backend, &mirror.error) {
+				(Some(backend), None) => backend,
+				_ => continue,
+			};
+
+			match f(&mut **backend) {
+				Ok(()) => (),
+				Err(Error::ArchiveNameConflict) => return Err(Error::ArchiveNameConflict),
+				Err(err) if on_failure == OnBackendFailure::Fail => return Err(err),
+				Err(err) => {
+					warn!("The backend '{}' failed, continuing without it: {}", mirror.path, err);
+					mirror.error = Some(err.to_string());
+				},
+			}
+		}
+
+		self.primary().map(|_| ())
+	}
+
+	fn primary(&mut self) -> Result<&mut Box<dyn Backend>> {
+		self.mirrors.iter_mut()
+			.filter(|mirror| mirror.error.is_none())
+			.find_map(|mirror| mirror.backend.as_mut())
+			.ok_or(Error::NoBackendsLeft)
+	}
+}
+
+impl Backend for MirrorBackend {
+	/// True only if every backend has the block, so that it gets stored to the ones that don't
+	fn block_exists(&mut self, id: &BlockId) -> Result<bool> {
+		let mut exists = true;
+
+		self.for_each(|backend| {
+			if exists {
+				exists = backend.block_exists(id)?;
+			}
+
+			Ok(())
+		})?;
+
+		Ok(exists)
+	}
+
+	fn store_block(&mut self, id: &BlockId, data: &EncryptedBlock) -> Result<()> {
+		self.for_each(|backend| {
+			if backend.block_exists(id)? {
+				return Ok(());
+			}
+
+			backend.store_block(id, data)
+		})
+	}
+
+	fn fetch_block(&mut self, id: &BlockId) -> Result<EncryptedBlock> {
+		self.primary()?.fetch_block(id)
+	}
+
+	fn list_blocks(&mut self) -> Result<Vec<(BlockId, u64)>> {
+		self.primary()?.list_blocks()
+	}
+
+	fn delete_block(&mut self, id: &BlockId) -> Result<()> {
+		self.for_each(|backend| backend.delete_block(id))
+	}
+
+	fn store_archive(&mut self, id: &ArchiveId, name: &EncryptedArchiveName, blocklist: &EncryptedArchiveBlocklist, data: &EncryptedArchiveMetadata) -> Result<()> {
+		self.for_each(|backend| backend.store_archive(id, name, blocklist, data))
+	}
+
+	fn fetch_archive(&mut self, id: &ArchiveId) -> Result<EncryptedArchiveMetadata> {
+		self.primary()?.fetch_archive(id)
+	}
+
+	fn fetch_archive_blocklist(&mut self, id: &ArchiveId) -> Result<Option<EncryptedArchiveBlocklist>> {
+		self.primary()?.fetch_archive_blocklist(id)
+	}
+
+	fn list_archives(&mut self) -> Result<Vec<(ArchiveId, EncryptedArchiveName)>> {
+		self.primary()?.list_archives()
+	}
+
+	fn delete_archive(&mut self, id: &ArchiveId) -> Result<()> {
+		self.for_each(|backend| backend.delete_archive(id))
+	}
+
+	fn flush(&mut self) -> Result<()> {
+		self.for_each(|backend| backend.flush())
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::{MirrorBackend, OnBackendFailure};
+	use crate::backend::{Backend, FileBackend};
+	use crate::error::Error;
+	use crate::keystore::{ArchiveId, BlockId, EncryptedArchiveBlocklist, EncryptedArchiveMetadata, EncryptedArchiveName, EncryptedBlock};
+
+	fn store(backend: &mut MirrorBackend) -> Result<(), Error> {
+		let block_id = BlockId::from_slice(&[1; 32]).unwrap();
+		let archive_id = ArchiveId::from_slice(&[2; 32]).unwrap();
+
+		backend.store_block(&block_id, &EncryptedBlock(vec![1, 2, 3]))?;
+		backend.store_archive(&archive_id, &EncryptedArchiveName(vec![1]), &EncryptedArchiveBlocklist([&[0; 32][..], &block_id[..]].concat()), &EncryptedArchiveMetadata(vec![1]))
+	}
+
+	#[test]
+	fn test_mirror() {
+		let dir1 = tempfile::Builder::new().prefix("preserve-test-").tempdir().unwrap();
+		let dir2 = tempfile::Builder::new().prefix("preserve-test-").tempdir().unwrap();
+		// Nothing can be created under a regular file, so this backend fails like an unplugged disk would
+		std::fs::write(dir1.path().join("file"), b"").unwrap();
+		let missing = dir1.path().join("file").join("backups");
+
+		let mut backend = MirrorBackend::new(OnBackendFailure::Fail);
+		backend.add("dir1", Box::new(FileBackend::new(dir1.path())));
+		backend.add("dir2", Box::new(FileBackend::new(dir2.path())));
+		store(&mut backend).unwrap();
+		assert!(backend.report().iter().all(|(_, err)| err.is_none()));
+
+		for dir in &[dir1.path(), dir2.path()] {
+			let mut file_backend = FileBackend::new(dir);
+			assert_eq!(file_backend.list_archives().unwrap().len(), 1);
+			assert_eq!(file_backend.fetch_block(&BlockId::from_slice(&[1; 32]).unwrap()).unwrap().0, vec![1, 2, 3]);
+		}
+
+		// A lost backend fails the backup...
+		let mut backend = MirrorBackend::new(OnBackendFailure::Fail);
+		backend.add("missing", Box::new(FileBackend::new(&missing)));
+		std::fs::create_dir(dir2.path().join("new")).unwrap();
+		backend.add("dir2", Box::new(FileBackend::new(dir2.path().join("new"))));
+		assert!(store(&mut backend).is_err());
+
+		// ...or is skipped
+		let mut backend = MirrorBackend::new(OnBackendFailure::Warn);
+		backend.add("missing", Box::new(FileBackend::new(&missing)));
+		backend.add("dir3", Box::new(FileBackend::new(dir2.path().join("new"))));
+		store(&mut backend).unwrap();
+		let report = backend.report();
+		assert!(report[0].1.is_some());
+		assert_eq!(report[1], ("dir3", None));
+
+		// Unless nothing is left
+		let mut backend = MirrorBackend::new(OnBackendFailure::Warn);
+		backend.add("missing", Box::new(FileBackend::new(&missing)));
+		assert!(matches!(store(&mut backend), Err(Error::NoBackendsLeft)));
+	}
+}
@@ -1,29 +1,124 @@
-use crate::keystore::{ArchiveId, EncryptedArchiveName, EncryptedArchiveMetadata, EncryptedBlock, BlockId};
+use crate::keystore::{ArchiveId, EncryptedArchiveName, EncryptedArchiveMetadata, EncryptedArchiveBlocklist, EncryptedBlock, BlockId};
 use crate::error::*;
 use url::Url;
 
+pub mod append_only;
 pub mod file;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod index;
+pub mod mirror;
+#[cfg(feature = "s3")]
+pub mod s3;
+#[cfg(feature = "sftp")]
+pub mod sftp;
 
+pub use crate::backend::append_only::AppendOnlyBackend;
 pub use crate::backend::file::FileBackend;
+pub use crate::backend::mirror::{MirrorBackend, OnBackendFailure};
+#[cfg(feature = "http")]
+pub use crate::backend::http::HttpBackend;
+#[cfg(feature = "s3")]
+pub use crate::backend::s3::S3Backend;
+#[cfg(feature = "sftp")]
+pub use crate::backend::sftp::SftpBackend;
 
 
 pub trait Backend {
 	fn block_exists(&mut self, id: &BlockId) -> Result<bool>;
 	fn store_block(&mut self, id: &BlockId, data: &EncryptedBlock) -> Result<()>;
 	fn fetch_block(&mut self, id: &BlockId) -> Result<EncryptedBlock>;
+	/// List every block in the backend, along with the number of bytes it occupies.
+	fn list_blocks(&mut self) -> Result<Vec<(BlockId, u64)>>;
+	fn delete_block(&mut self, id: &BlockId) -> Result<()>;
 
-	fn store_archive(&mut self, id: &ArchiveId, name: &EncryptedArchiveName, data: &EncryptedArchiveMetadata) -> Result<()>;
+	fn store_archive(&mut self, id: &ArchiveId, name: &EncryptedArchiveName, blocklist: &EncryptedArchiveBlocklist, data: &EncryptedArchiveMetadata) -> Result<()>;
 	fn fetch_archive(&mut self, id: &ArchiveId) -> Result<EncryptedArchiveMetadata>;
+	/// Returns None for archives created before blocklists were stored.
+	fn fetch_archive_blocklist(&mut self, id: &ArchiveId) -> Result<Option<EncryptedArchiveBlocklist>>;
 	fn list_archives(&mut self) -> Result<Vec<(ArchiveId, EncryptedArchiveName)>>;
+	/// Removes the archive, but not the blocks it references.  Those are left for garbage collection.
+	fn delete_archive(&mut self, id: &ArchiveId) -> Result<()>;
+
+	/// Finish any work the backend put off, like writing buffered blocks or compacting packs that blocks were removed from.
+	/// Backends also do this when dropped, but can only log errors there.
+	fn flush(&mut self) -> Result<()> {
+		Ok(())
+	}
 }
 
 
 /// Given a backend path, return a Box'd Backend.
+/// `?append-only=1` wraps the backend in an AppendOnlyBackend.
 pub fn backend_from_backend_path(path: &str) -> Result<Box<dyn Backend>> {
 	let url = Url::parse(path).map_err(|_| Error::BadBackendPath("Given backend path could not be understood.".to_string()))?;
 
-	match url.scheme() {
-		"file" => Ok(Box::new(FileBackend::new(url.path()))),
+	let append_only = match url.query_pairs().find(|(key, _)| key == "append-only").as_ref().map(|(_, value)| &value[..]) {
+		None | Some("0") | Some("false") => false,
+		Some("1") | Some("true") => true,
+		Some(value) => return Err(Error::BadBackendPath(format!("Invalid append-only value: {}", value))),
+	};
+
+	let backend: Box<dyn Backend> = match url.scheme() {
+		"file" => Box::new(FileBackend::new(url.path())),
+		#[cfg(feature = "http")]
+		"http" | "https" => Box::new(HttpBackend::new(&url)?),
+		#[cfg(feature = "s3")]
+		"s3" => Box::new(S3Backend::new(&url)?),
+		#[cfg(feature = "sftp")]
+		"sftp" => Box::new(SftpBackend::new(&url)?),
 		e => return Err(Error::BadBackendPath(format!("Unknown backend: {}", e))),
+	};
+
+	if append_only {
+		return Ok(Box::new(AppendOnlyBackend::new(backend)));
+	}
+
+	Ok(backend)
+}
+
+
+#[cfg(test)]
+pub mod test {
+	use super::{Backend, FileBackend};
+	use crate::error::Error;
+	use crate::keystore::{ArchiveId, BlockId, EncryptedArchiveBlocklist, EncryptedArchiveMetadata, EncryptedArchiveName, EncryptedBlock};
+	use rand::Rng;
+
+	/// Run a backend through everything preserve does with one.  Uses random ids, so it can be pointed at a backend that's
+	/// already in use.
+	pub fn exercise_backend(backend: &mut dyn Backend) {
+		let mut rng = rand::thread_rng();
+		let block_id = BlockId::from_slice(&rng.gen::<[u8; 32]>()).unwrap();
+		let archive_id = ArchiveId::from_slice(&rng.gen::<[u8; 32]>()).unwrap();
+		let blocklist = || EncryptedArchiveBlocklist([&[0; 32][..], &block_id[..]].concat());
+
+		// Listing has to work before anything is stored, since a new backend starts out empty
+		backend.list_archives().unwrap();
+		backend.list_blocks().unwrap();
+
+		assert!(!backend.block_exists(&block_id).unwrap());
+		backend.store_block(&block_id, &EncryptedBlock(vec![1, 2, 3])).unwrap();
+		assert!(backend.block_exists(&block_id).unwrap());
+		assert_eq!(backend.fetch_block(&block_id).unwrap().0, vec![1, 2, 3]);
+		assert!(backend.list_blocks().unwrap().contains(&(block_id, 3)));
+
+		backend.store_archive(&archive_id, &EncryptedArchiveName(vec![4]), &blocklist(), &EncryptedArchiveMetadata(vec![5])).unwrap();
+		assert!(matches!(backend.store_archive(&archive_id, &EncryptedArchiveName(vec![4]), &blocklist(), &EncryptedArchiveMetadata(vec![6])), Err(Error::ArchiveNameConflict)));
+		assert_eq!(backend.fetch_archive(&archive_id).unwrap().0, vec![5]);
+		assert_eq!(backend.fetch_archive_blocklist(&archive_id).unwrap().unwrap().0, blocklist().0);
+		assert!(backend.list_archives().unwrap().iter().any(|(id, name)| *id == archive_id && name.0 == vec![4]));
+
+		backend.delete_archive(&archive_id).unwrap();
+		assert!(!backend.list_archives().unwrap().iter().any(|(id, _)| *id == archive_id));
+		backend.delete_block(&block_id).unwrap();
+		assert!(!backend.block_exists(&block_id).unwrap());
+	}
+
+	#[test]
+	fn test_file_backend() {
+		let dir = tempfile::Builder::new().prefix("preserve-test-").tempdir().unwrap();
+
+		exercise_backend(&mut FileBackend::new(dir.path()));
 	}
 }
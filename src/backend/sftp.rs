@@ -0,0 +1,342 @@
+use crate::backend::Backend;
+use crate::keystore::{ArchiveId, EncryptedArchiveName, EncryptedArchiveMetadata, EncryptedArchiveBlocklist, EncryptedBlock, BlockId};
+use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::env;
+use rand::rngs::OsRng;
+use rand::Rng;
+use ssh2::{CheckResult, KnownHostFileKind, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+use std::str::FromStr;
+use url::Url;
+use crate::error::*;
+
+
+/// A backend on an SSH server, accessed using SFTP.  Uses the same layout as FileBackend did before packs:
+/// blocks/xx/yy/<id> and archives/<id>.{name,blocklist,metadata}.
+/// The server's host key must already be in ~/.ssh/known_hosts.  Authentication uses the SSH agent, then the usual
+/// keys in ~/.ssh, then the password in the URL, if any.
+pub struct SftpBackend {
+	backup_dir: PathBuf,
+	sftp: Sftp,
+	// Keeps the connection open
+	_session: Session,
+}
+
+impl SftpBackend {
+	/// Connect to sftp://user@host[:port]/path
+	pub fn new(url: &Url) -> Result<SftpBackend> {
+		let host = url.host_str().ok_or_else(|| Error::BadBackendPath("The sftp backend path is missing a host".to_string()))?;
+		let port = url.port().unwrap_or(22);
+		let username = match url.username() {
+			"" => env::var("USER").map_err(|_| Error::BadBackendPath("The sftp backend path is missing a user".to_string()))?,
+			username => username.to_owned(),
+		};
+
+		let tcp = TcpStream::connect((host, port))?;
+		let mut session = Session::new().map_err(ssh_error)?;
+		session.set_tcp_stream(tcp);
+		session.handshake().map_err(ssh_error)?;
+
+		check_host_key(&session, host, port)?;
+		authenticate(&session, &username, url.password())?;
+
+		let sftp = session.sftp().map_err(ssh_error)?;
+
+		Ok(SftpBackend {
+			backup_dir: PathBuf::from(url.path()),
+			sftp,
+			_session: session,
+		})
+	}
+
+	fn block_path(&self, id: &BlockId) -> PathBuf {
+		let block_id = id.to_string();
+		let dir1 = &block_id[0..2];
+		let dir2 = &block_id[2..4];
+
+		self.backup_dir.join("blocks").join(dir1).join(dir2).join(&block_id)
+	}
+
+	fn exists(&self, path: &Path) -> bool {
+		self.sftp.stat(path).is_ok()
+	}
+
+	/// Like `mkdir -p`.  Errors are ignored, since the directories usually exist already; writing into them will fail anyway if they don't.
+	fn create_dir_all(&self, path: &Path) {
+		let mut partial = PathBuf::new();
+
+		for component in path.components() {
+			partial.push(component);
+
+			if !self.exists(&partial) {
+				self.sftp.mkdir(&partial, 0o755).unwrap_or(());
+			}
+		}
+	}
+
+	/// Write to a temporary file and then rename it into place, so destination is never seen partially written.
+	/// The rename is done without the OVERWRITE flag, so if destination already exists this returns AlreadyExists.
+	/// (OpenSSH's SFTPv3 server ignores rename flags, but never replaces an existing file anyway.)
+	fn safely_write_file(&self, destination: &Path, data: &[u8]) -> Result<()> {
+		let temppath = {
+			let tempname: String = OsRng.sample_iter(&rand::distributions::Alphanumeric).take(32).collect();
+			let temppath = self.backup_dir.join("temp");
+			self.create_dir_all(&temppath);
+			temppath.join(tempname)
+		};
+
+		{
+			// Archives and Blocks should be stored as world readonly
+			let mut file = self.sftp.open_mode(&temppath, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE, 0o444, OpenType::File).map_err(ssh_error)?;
+
+			if let Err(err) = file.write_all(data) {
+				drop(file);
+				self.sftp.unlink(&temppath).unwrap_or(());
+				return Err(err.into());
+			}
+		}
+
+		if let Err(err) = self.sftp.rename(&temppath, destination, Some(RenameFlags::ATOMIC | RenameFlags::NATIVE)) {
+			self.sftp.unlink(&temppath).unwrap_or(());
+
+			if self.exists(destination) {
+				return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("'{}' already exists", destination.display())).into());
+			}
+
+			return Err(ssh_error(err));
+		}
+
+		Ok(())
+	}
+
+	fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+		let mut file = self.sftp.open(path).map_err(ssh_error)?;
+		let mut data = Vec::new();
+
+		file.read_to_end(&mut data)?;
+
+		Ok(data)
+	}
+
+	fn archive_path(&self, id: &ArchiveId, extension: &str) -> PathBuf {
+		self.backup_dir.join("archives").join(format!("{}.{}", id.to_string(), extension))
+	}
+}
+
+impl Backend for SftpBackend {
+	fn block_exists(&mut self, id: &BlockId) -> Result<bool> {
+		Ok(self.exists(&self.block_path(id)))
+	}
+
+	fn store_block(&mut self, id: &BlockId, data: &EncryptedBlock) -> Result<()> {
+		let path = self.block_path(id);
+
+		if self.exists(&path) {
+			return Ok(());
+		}
+
+		self.create_dir_all(path.parent().expect("internal error"));
+
+		match self.safely_write_file(&path, &data.0) {
+			// Another backup stored the same block first
+			Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+			result => result,
+		}
+	}
+
+	fn fetch_block(&mut self, id: &BlockId) -> Result<EncryptedBlock> {
+		Ok(EncryptedBlock(self.read_file(&self.block_path(id))?))
+	}
+
+	fn list_blocks(&mut self) -> Result<Vec<(BlockId, u64)>> {
+		let mut blocks = Vec::new();
+		let blocks_dir = self.backup_dir.join("blocks");
+
+		if !self.exists(&blocks_dir) {
+			return Ok(blocks);
+		}
+
+		// Blocks are stored as blocks/xx/yy/<id>
+		for (dir1, _) in self.sftp.readdir(&blocks_dir).map_err(ssh_error)? {
+			for (dir2, _) in self.sftp.readdir(&dir1).map_err(ssh_error)? {
+				for (path, stat) in self.sftp.readdir(&dir2).map_err(ssh_error)? {
+					let filename_str = path.file_name().and_then(|filename| filename.to_str()).ok_or(Error::InvalidBlockId)?;
+					let block_id = BlockId::from_str(filename_str)?;
+
+					blocks.push((block_id, stat.size.unwrap_or(0)));
+				}
+			}
+		}
+
+		Ok(blocks)
+	}
+
+	fn delete_block(&mut self, id: &BlockId) -> Result<()> {
+		let path = self.block_path(id);
+
+		if !self.exists(&path) {
+			return Err(Error::BlockNotFound);
+		}
+
+		self.sftp.unlink(&path).map_err(ssh_error)
+	}
+
+	fn store_archive(&mut self, id: &ArchiveId, name: &EncryptedArchiveName, blocklist: &EncryptedArchiveBlocklist, data: &EncryptedArchiveMetadata) -> Result<()> {
+		let name_path = self.archive_path(id, "name");
+		let blocklist_path = self.archive_path(id, "blocklist");
+		let metadata_path = self.archive_path(id, "metadata");
+
+		if self.exists(&name_path) {
+			return Err(Error::ArchiveNameConflict);
+		}
+
+		self.create_dir_all(&self.backup_dir.join("archives"));
+
+		// None of the renames replace existing files.  So if the blocklist or metadata already exist, another create of the
+		// same name is in progress (or was interrupted, in which case delete_archive removes what it left); and once
+		// they're written, they're ours.
+		// The name goes last, so an interrupted store never leaves a listed archive that can't be fetched.
+		let conflict = |err: Error| match err {
+			Error::Io(ref io_err) if io_err.kind() == io::ErrorKind::AlreadyExists => Error::ArchiveNameConflict,
+			err => err,
+		};
+
+		self.safely_write_file(&blocklist_path, &blocklist.0).map_err(conflict)?;
+
+		if let Err(err) = self.safely_write_file(&metadata_path, &data.0) {
+			self.sftp.unlink(&blocklist_path).unwrap_or(());
+			return Err(conflict(err));
+		}
+
+		if let Err(err) = self.safely_write_file(&name_path, &name.0) {
+			self.sftp.unlink(&blocklist_path).unwrap_or(());
+			self.sftp.unlink(&metadata_path).unwrap_or(());
+			return Err(conflict(err));
+		}
+
+		Ok(())
+	}
+
+	fn fetch_archive(&mut self, id: &ArchiveId) -> Result<EncryptedArchiveMetadata> {
+		Ok(EncryptedArchiveMetadata(self.read_file(&self.archive_path(id, "metadata"))?))
+	}
+
+	fn fetch_archive_blocklist(&mut self, id: &ArchiveId) -> Result<Option<EncryptedArchiveBlocklist>> {
+		let path = self.archive_path(id, "blocklist");
+
+		if !self.exists(&path) {
+			return Ok(None);
+		}
+
+		Ok(Some(EncryptedArchiveBlocklist(self.read_file(&path)?)))
+	}
+
+	fn list_archives(&mut self) -> Result<Vec<(ArchiveId, EncryptedArchiveName)>> {
+		let mut archives = Vec::new();
+		let archives_dir = self.backup_dir.join("archives");
+
+		if !self.exists(&archives_dir) {
+			return Ok(archives);
+		}
+
+		for (path, _) in self.sftp.readdir(&archives_dir).map_err(ssh_error)? {
+			if path.extension() != Some("name".as_ref()) {
+				continue;
+			}
+
+			let filename_str = path.file_stem().and_then(|filename| filename.to_str()).ok_or(Error::InvalidArchiveId)?;
+			let archive_id = ArchiveId::from_str(filename_str).map_err(|_| Error::InvalidArchiveId)?;
+			let encrypted_archive_name = EncryptedArchiveName(self.read_file(&path)?);
+
+			archives.push((archive_id, encrypted_archive_name));
+		}
+
+		Ok(archives)
+	}
+
+	/// Also removes the parts left behind by an interrupted store_archive, which would otherwise conflict with every later store.
+	fn delete_archive(&mut self, id: &ArchiveId) -> Result<()> {
+		let paths = [self.archive_path(id, "name"), self.archive_path(id, "blocklist"), self.archive_path(id, "metadata")];
+		let mut found = false;
+
+		// The name goes first, so that an interrupted delete never leaves a listed archive that can't be fetched.
+		for path in &paths {
+			if self.exists(path) {
+				self.sftp.unlink(path).map_err(ssh_error)?;
+				found = true;
+			}
+		}
+
+		if !found {
+			return Err(Error::ArchiveNotFound);
+		}
+
+		Ok(())
+	}
+}
+
+
+fn ssh_error(err: ssh2::Error) -> Error {
+	Error::Io(err.into())
+}
+
+
+/// Refuse to talk to servers whose host key isn't in ~/.ssh/known_hosts
+fn check_host_key(session: &Session, host: &str, port: u16) -> Result<()> {
+	let known_hosts_path = env::var("HOME").map(|home| Path::new(&home).join(".ssh").join("known_hosts")).map_err(|_| Error::BadBackendPath("Unable to find ~/.ssh/known_hosts".to_string()))?;
+	let mut known_hosts = session.known_hosts().map_err(ssh_error)?;
+	known_hosts.read_file(&known_hosts_path, KnownHostFileKind::OpenSSH).map_err(ssh_error)?;
+
+	let (key, _) = session.host_key().ok_or_else(|| Error::BadBackendPath(format!("'{}' did not send a host key", host)))?;
+
+	match known_hosts.check_port(host, port, key) {
+		CheckResult::Match => Ok(()),
+		CheckResult::NotFound => Err(Error::BadBackendPath(format!("The host key for '{}' is not in {}.  Connect to it once with ssh to add it.", host, known_hosts_path.display()))),
+		CheckResult::Mismatch => Err(Error::BadBackendPath(format!("The host key for '{}' does not match the one in {}", host, known_hosts_path.display()))),
+		CheckResult::Failure => Err(Error::BadBackendPath(format!("Unable to check the host key for '{}'", host))),
+	}
+}
+
+
+fn authenticate(session: &Session, username: &str, password: Option<&str>) -> Result<()> {
+	if session.userauth_agent(username).is_ok() && session.authenticated() {
+		return Ok(());
+	}
+
+	if let Ok(home) = env::var("HOME") {
+		for key in &["id_ed25519", "id_ecdsa", "id_rsa"] {
+			let private_key = Path::new(&home).join(".ssh").join(key);
+
+			if private_key.exists() && session.userauth_pubkey_file(username, None, &private_key, None).is_ok() && session.authenticated() {
+				return Ok(());
+			}
+		}
+	}
+
+	if let Some(password) = password {
+		if session.userauth_password(username, password).is_ok() && session.authenticated() {
+			return Ok(());
+		}
+	}
+
+	Err(Error::BadBackendPath(format!("Unable to authenticate as '{}'", username)))
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::SftpBackend;
+	use crate::backend::test::exercise_backend;
+	use url::Url;
+
+	// Needs an SSH server.  For example, with a local sshd that accepts your key:
+	// PRESERVE_TEST_SFTP=sftp://localhost/tmp/preserve-test cargo test --features sftp -- --ignored
+	#[test]
+	#[ignore]
+	fn test_sftp_server() {
+		let url = std::env::var("PRESERVE_TEST_SFTP").expect("PRESERVE_TEST_SFTP is not set");
+
+		exercise_backend(&mut SftpBackend::new(&Url::parse(&url).unwrap()).unwrap());
+	}
+}
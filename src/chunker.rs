@@ -0,0 +1,267 @@
+use crate::keystore::KeyStore;
+use serde_derive::{Deserialize, Serialize};
+use std::cmp;
+use std::io::{self, Read};
+
+
+/// How files are split into blocks.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChunkingStrategy {
+	/// Blocks of exactly size bytes (the last block of a file may be shorter).
+	Fixed { size: u64 },
+	/// Content defined chunking using FastCDC.  Chunk boundaries depend on the content around them, so inserting data
+	/// into a file only changes the blocks near the insertion.  avg_size must be a power of two.
+	FastCdc { min_size: u64, avg_size: u64, max_size: u64 },
+}
+
+/// Archives which don't record a strategy were split into fixed 1MB blocks.
+pub const LEGACY_STRATEGY: ChunkingStrategy = ChunkingStrategy::Fixed { size: 1024 * 1024 };
+
+/// Used for `--chunker fastcdc` when no sizes are given.
+pub const DEFAULT_FASTCDC_STRATEGY: ChunkingStrategy = ChunkingStrategy::FastCdc { min_size: 256 * 1024, avg_size: 1024 * 1024, max_size: 4 * 1024 * 1024 };
+
+/// Blocks are held in memory while being encrypted, so keep them reasonable.
+const MAX_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+
+impl Default for ChunkingStrategy {
+	fn default() -> ChunkingStrategy {
+		LEGACY_STRATEGY
+	}
+}
+
+impl ChunkingStrategy {
+	/// Parse a strategy name ("fixed" or "fastcdc") and optional sizes.  Fixed takes a single SIZE, FastCDC takes MIN,AVG,MAX.
+	/// Sizes are in bytes, with an optional K or M suffix.
+	pub fn parse(name: &str, sizes: Option<&str>) -> Option<ChunkingStrategy> {
+		let sizes = match sizes {
+			Some(sizes) => Some(sizes.split(',').map(parse_size).collect::<Option<Vec<u64>>>()?),
+			None => None,
+		};
+
+		let strategy = match (name, sizes.as_ref().map(|sizes| &sizes[..])) {
+			("fixed", None) => LEGACY_STRATEGY,
+			("fixed", Some(&[size])) => ChunkingStrategy::Fixed { size },
+			("fastcdc", None) => DEFAULT_FASTCDC_STRATEGY,
+			("fastcdc", Some(&[min_size, avg_size, max_size])) => ChunkingStrategy::FastCdc { min_size, avg_size, max_size },
+			_ => return None,
+		};
+
+		if strategy.is_valid() {
+			Some(strategy)
+		} else {
+			None
+		}
+	}
+
+	fn is_valid(&self) -> bool {
+		match *self {
+			ChunkingStrategy::Fixed { size } => size > 0 && size <= MAX_CHUNK_SIZE,
+			ChunkingStrategy::FastCdc { min_size, avg_size, max_size } => {
+				// The normalized masks use two bits more and less than avg_size
+				avg_size.is_power_of_two() && avg_size >= 256 && min_size < avg_size && avg_size < max_size && max_size <= MAX_CHUNK_SIZE
+			},
+		}
+	}
+
+	/// The largest chunk this strategy will produce.
+	fn max_size(&self) -> u64 {
+		match *self {
+			ChunkingStrategy::Fixed { size } => size,
+			ChunkingStrategy::FastCdc { max_size, .. } => max_size,
+		}
+	}
+}
+
+
+fn parse_size(s: &str) -> Option<u64> {
+	let s = s.trim();
+	let (number, multiplier) = match s.chars().last()? {
+		'k' | 'K' => (&s[..s.len() - 1], 1024),
+		'm' | 'M' => (&s[..s.len() - 1], 1024 * 1024),
+		_ => (s, 1),
+	};
+
+	number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+
+/// Finds chunk boundaries.  FastCDC is keyed by a gear table derived from the KeyStore.
+pub struct Chunker {
+	strategy: ChunkingStrategy,
+	gear: [u64; 256],
+	/// Mask used before reaching avg_size; harder to match, so small chunks are rare
+	mask_small: u64,
+	/// Mask used after reaching avg_size; easier to match, so large chunks are rare
+	mask_large: u64,
+}
+
+impl Chunker {
+	pub fn new(strategy: ChunkingStrategy, keystore: &KeyStore) -> Chunker {
+		let (gear, mask_small, mask_large) = match strategy {
+			ChunkingStrategy::Fixed { .. } => ([0; 256], 0, 0),
+			ChunkingStrategy::FastCdc { avg_size, .. } => {
+				let bits = avg_size.trailing_zeros();
+				// The gear hash mixes recent bytes into the top bits, so match against those
+				(keystore.chunker_gear_table(), !0u64 << (64 - (bits + 2)), !0u64 << (64 - (bits - 2)))
+			},
+		};
+
+		Chunker {
+			strategy,
+			gear,
+			mask_small,
+			mask_large,
+		}
+	}
+
+	pub fn strategy(&self) -> ChunkingStrategy {
+		self.strategy
+	}
+
+	/// Length of the first chunk in data.  data is assumed to be all that's left of the stream, or at least max_size long.
+	fn cut_point(&self, data: &[u8]) -> usize {
+		let (min_size, avg_size, max_size) = match self.strategy {
+			ChunkingStrategy::Fixed { size } => return cmp::min(size as usize, data.len()),
+			ChunkingStrategy::FastCdc { min_size, avg_size, max_size } => (min_size as usize, avg_size as usize, max_size as usize),
+		};
+
+		if data.len() <= min_size {
+			return data.len();
+		}
+
+		let end = cmp::min(data.len(), max_size);
+		let normal = cmp::min(avg_size, end);
+		let mut hash = 0u64;
+
+		for (i, &byte) in data.iter().enumerate().take(normal).skip(min_size) {
+			hash = (hash << 1).wrapping_add(self.gear[byte as usize]);
+
+			if hash & self.mask_small == 0 {
+				return i + 1;
+			}
+		}
+
+		for (i, &byte) in data.iter().enumerate().take(end).skip(normal) {
+			hash = (hash << 1).wrapping_add(self.gear[byte as usize]);
+
+			if hash & self.mask_large == 0 {
+				return i + 1;
+			}
+		}
+
+		end
+	}
+
+	/// Split reader into chunks.
+	pub fn chunks<R: Read>(&self, reader: R) -> ChunkReader<'_, R> {
+		ChunkReader {
+			chunker: self,
+			reader,
+			pending: Vec::new(),
+			eof: false,
+		}
+	}
+}
+
+
+/// Reads a stream one chunk at a time.
+pub struct ChunkReader<'a, R> {
+	chunker: &'a Chunker,
+	reader: R,
+	/// Data read from reader but not yet returned
+	pending: Vec<u8>,
+	eof: bool,
+}
+
+impl<'a, R: Read> ChunkReader<'a, R> {
+	/// Read the next chunk into buffer, replacing its contents.  buffer is left empty at the end of the stream.
+	pub fn next_chunk(&mut self, buffer: &mut Vec<u8>) -> io::Result<()> {
+		let max_size = self.chunker.strategy.max_size();
+
+		if !self.eof && (self.pending.len() as u64) < max_size {
+			let wanted = max_size - self.pending.len() as u64;
+			let read = (&mut self.reader).take(wanted).read_to_end(&mut self.pending)?;
+			self.eof = (read as u64) < wanted;
+		}
+
+		let cut = self.chunker.cut_point(&self.pending);
+
+		buffer.clear();
+		buffer.extend(self.pending.drain(..cut));
+
+		Ok(())
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::{Chunker, ChunkingStrategy, LEGACY_STRATEGY};
+	use crate::keystore::KeyStore;
+	use rand::{RngCore, SeedableRng};
+	use rand::rngs::StdRng;
+
+	fn chunk(chunker: &Chunker, data: &[u8]) -> Vec<Vec<u8>> {
+		let mut reader = chunker.chunks(data);
+		let mut chunks = Vec::new();
+
+		loop {
+			let mut buffer = Vec::new();
+			reader.next_chunk(&mut buffer).unwrap();
+
+			if buffer.is_empty() {
+				return chunks;
+			}
+
+			chunks.push(buffer);
+		}
+	}
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(ChunkingStrategy::parse("fixed", None), Some(LEGACY_STRATEGY));
+		assert_eq!(ChunkingStrategy::parse("fixed", Some("64K")), Some(ChunkingStrategy::Fixed { size: 64 * 1024 }));
+		assert_eq!(ChunkingStrategy::parse("fastcdc", Some("256K,1M,4M")), Some(ChunkingStrategy::FastCdc { min_size: 256 * 1024, avg_size: 1024 * 1024, max_size: 4 * 1024 * 1024 }));
+		assert_eq!(ChunkingStrategy::parse("fastcdc", Some("256K,1000K,4M")), None);
+		assert_eq!(ChunkingStrategy::parse("fastcdc", Some("1M")), None);
+		assert_eq!(ChunkingStrategy::parse("fixed", Some("0")), None);
+		assert_eq!(ChunkingStrategy::parse("buzhash", None), None);
+	}
+
+	#[test]
+	fn test_fastcdc() {
+		let keystore = KeyStore::new();
+		let strategy = ChunkingStrategy::FastCdc { min_size: 2 * 1024, avg_size: 8 * 1024, max_size: 32 * 1024 };
+		let chunker = Chunker::new(strategy, &keystore);
+		let mut data = vec![0u8; 1024 * 1024];
+		StdRng::seed_from_u64(0).fill_bytes(&mut data);
+
+		let chunks = chunk(&chunker, &data);
+		assert_eq!(chunks.concat(), data);
+		assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() >= 2 * 1024 && chunk.len() <= 32 * 1024));
+		assert!(chunks.len() > 64 && chunks.len() < 256);
+
+		// Inserting data near the start should only change the chunks around it
+		let mut modified = data.clone();
+		modified.splice(1000..1000, b"inserted".iter().cloned());
+		let modified_chunks = chunk(&chunker, &modified);
+		assert_eq!(modified_chunks.concat(), modified);
+		let unchanged = modified_chunks.iter().filter(|chunk| chunks.contains(chunk)).count();
+		assert!(unchanged >= chunks.len() - 2);
+
+		// A different key gives different boundaries
+		let other_chunks = chunk(&Chunker::new(strategy, &KeyStore::new()), &data);
+		assert_ne!(chunks, other_chunks);
+	}
+
+	#[test]
+	fn test_fixed() {
+		let chunker = Chunker::new(ChunkingStrategy::Fixed { size: 1000 }, &KeyStore::new());
+		let data = vec![7u8; 2500];
+
+		let chunks = chunk(&chunker, &data);
+		assert_eq!(chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>(), vec![1000, 1000, 500]);
+	}
+}
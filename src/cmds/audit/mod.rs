@@ -0,0 +1,111 @@
+use crate::keystore::{KeyStore, BlockId};
+use crate::archive_log::ArchiveLog;
+use crate::backend;
+use std::collections::HashSet;
+use std::process;
+use clap::ArgMatches;
+use log::{error, info, warn};
+
+
+pub fn execute(args: &ArgMatches) {
+	let args_keyfile = args.value_of("keyfile").expect("internal error");
+	let args_backend = args.value_of("backend").expect("internal error");
+	let args_archive_log = args.value_of("archive-log").expect("internal error");
+
+	let keystore = match KeyStore::load_from_path(args_keyfile) {
+		Ok(keystore) => keystore,
+		Err(err) => {
+			error!("Unable to load keyfile: {}", err);
+			process::exit(1);
+		}
+	};
+
+	let mut backend = match backend::backend_from_backend_path(args_backend) {
+		Ok(backend) => backend,
+		Err(err) => {
+			error!("Unable to load backend: {}", err);
+			process::exit(1);
+		}
+	};
+
+	let archive_log = match ArchiveLog::open(args_archive_log, &keystore) {
+		Ok(archive_log) => archive_log,
+		Err(err) => {
+			error!("Unable to open the archive log: {}", err);
+			process::exit(1);
+		}
+	};
+
+	let archives: HashSet<_> = match backend.list_archives() {
+		Ok(archives) => archives.into_iter().map(|(archive_id, _)| archive_id).collect(),
+		Err(err) => {
+			error!("There was a problem listing the archives: {}", err);
+			process::exit(1);
+		}
+	};
+
+	let blocks: HashSet<BlockId> = match backend.list_blocks() {
+		Ok(blocks) => blocks.into_iter().map(|(block_id, _)| block_id).collect(),
+		Err(err) => {
+			error!("There was a problem listing the blocks: {}", err);
+			process::exit(1);
+		}
+	};
+
+	let mut missing_archives = 0;
+	let mut missing_blocks: HashSet<BlockId> = HashSet::new();
+	// Backups whose blocks couldn't be checked
+	let mut missing_blocklists = 0;
+	let mut unreadable_blocklists = 0;
+
+	for (archive_id, encrypted_name) in archive_log.entries() {
+		// The log is authenticated, so this can't fail
+		let name = keystore.decrypt_archive_name(archive_id, encrypted_name).unwrap_or_else(|_| archive_id.to_string());
+
+		if !archives.contains(archive_id) {
+			error!("MISSING: The backup '{}' has been removed from the backend", name);
+			missing_archives += 1;
+			continue;
+		}
+
+		let blocklist = match backend.fetch_archive_blocklist(archive_id) {
+			Ok(Some(encrypted_blocklist)) => keystore.decrypt_archive_blocklist(archive_id, &encrypted_blocklist),
+			Ok(None) => {
+				warn!("The backup '{}' does not have a blocklist, so its blocks were not checked", name);
+				missing_blocklists += 1;
+				continue;
+			},
+			Err(err) => Err(err),
+		};
+
+		match blocklist {
+			Ok(blocklist) => {
+				let missing: Vec<&BlockId> = blocklist.iter().filter(|block_id| !blocks.contains(block_id)).collect();
+
+				if !missing.is_empty() {
+					error!("MISSING: {} blocks used by the backup '{}' have been removed from the backend", missing.len(), name);
+					missing_blocks.extend(missing.into_iter().copied());
+				}
+			},
+			Err(err) => {
+				error!("CRITICAL ERROR: The blocklist of the backup '{}' could not be read: {}", name, err);
+				unreadable_blocklists += 1;
+			},
+		}
+	}
+
+	if missing_archives == 0 && missing_blocks.is_empty() && missing_blocklists == 0 && unreadable_blocklists == 0 {
+		info!("All {} backups in the archive log, and their blocks, are still on the backend", archive_log.entries().len());
+		return;
+	}
+
+	if missing_archives > 0 || !missing_blocks.is_empty() {
+		error!("{} of {} backups in the archive log, and {} blocks, have been removed from the backend", missing_archives, archive_log.entries().len(), missing_blocks.len());
+	}
+
+	if missing_blocklists > 0 || unreadable_blocklists > 0 {
+		error!("The blocks of {} backups could not be checked: {} have no blocklist and {} blocklists could not be read", missing_blocklists + unreadable_blocklists, missing_blocklists, unreadable_blocklists);
+	}
+
+	process::exit(1);
+}
@@ -0,0 +1,98 @@
+use crate::keystore::KeyStore;
+use crate::backend::{self, Backend};
+use crate::archive::{Archive, File};
+use crate::compression::Compression;
+use crate::error::*;
+use crate::sparse::DataWriter;
+use clap::ArgMatches;
+use log::error;
+use std::io::{self, BufWriter, Write};
+use std::process;
+
+
+pub fn execute(args: &ArgMatches) {
+	let args_keyfile = args.value_of("keyfile").expect("internal error");
+	let args_backend = args.value_of("backend").expect("internal error");
+	let backup_name = args.value_of("NAME").expect("internal error");
+	let path = args.value_of("PATH").expect("internal error").trim_matches('/');
+
+	// Nothing but the file's contents should be written to stdout, so only errors are logged, and failures
+	// exit with a non-zero status so scripts don't mistake partial output for the real thing.
+	let keystore = match KeyStore::load_from_path(args_keyfile) {
+		Ok(keystore) => keystore,
+		Err(err) => {
+			error!("Unable to load keyfile: {}", err);
+			process::exit(1);
+		}
+	};
+
+	let mut backend = match backend::backend_from_backend_path(args_backend) {
+		Ok(backend) => backend,
+		Err(err) => {
+			error!("Unable to load backend: {}", err);
+			process::exit(1);
+		}
+	};
+
+	let archive = match Archive::fetch(backup_name, &keystore, &mut *backend) {
+		Ok(archive) => archive,
+		Err(err) => {
+			error!("There was a problem fetching the backup: {}", err);
+			process::exit(1);
+		}
+	};
+
+	let file = match archive.files.iter().find(|file| file.path == path) {
+		Some(file) => file,
+		None => {
+			error!("'{}' does not exist in the backup", path);
+			process::exit(1);
+		}
+	};
+
+	if let Some(ref symlink) = file.symlink {
+		error!("'{}' is a symlink to '{}'", path, symlink);
+		process::exit(1);
+	}
+
+	if file.is_dir {
+		error!("'{}' is a directory", path);
+		process::exit(1);
+	}
+
+	if file.special.is_some() {
+		error!("'{}' is not a regular file", path);
+		process::exit(1);
+	}
+
+	let stdout = io::stdout();
+	let mut writer = BufWriter::new(stdout.lock());
+
+	match write_file(file, archive.compression(), &mut writer, &keystore, &mut *backend) {
+		Ok(written) if written == file.size => (),
+		Ok(written) => {
+			error!("The extracted size of '{}' did not match what was expected: {} != {}", path, written, file.size);
+			process::exit(1);
+		},
+		Err(err) => {
+			error!("There was a problem extracting '{}': {}", path, err);
+			process::exit(1);
+		},
+	}
+}
+
+
+/// Fetch, decrypt and write all of the file's blocks, in order, returning the number of bytes written.
+fn write_file<W: Write>(file: &File, compression: Compression, writer: &mut W, keystore: &KeyStore, backend: &mut dyn Backend) -> Result<u64> {
+	// Holes in sparse files are written out as zeros
+	let mut writer = DataWriter::zero_filling(writer, &file.holes);
+
+	for block_id in &file.blocks {
+		let encrypted_block = backend.fetch_block(block_id)?;
+		let plaintext = keystore.decrypt_block(block_id, &encrypted_block, compression)?;
+
+		writer.write_all(&plaintext)?;
+	}
+
+	Ok(writer.finish()?)
+}
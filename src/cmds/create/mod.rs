@@ -1,30 +1,50 @@
 use rusqlite::types::ToSql;
 use crate::keystore::{KeyStore, BlockId};
 use std::fs;
-use std::io::{Read, BufReader};
 use std::path::{Path, PathBuf};
-use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::string::ToString;
-use crate::backend::{self, Backend};
-use crate::archive::{self, Archive};
+use crate::backend::{self, Backend, MirrorBackend, OnBackendFailure};
+use crate::archive::{self, Archive, ArchiveStats, CreateOptions, Hole, SpecialFile};
 use rusqlite;
-use std::collections::{HashSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashSet, HashMap};
 use std::env;
 use clap::ArgMatches;
 use crate::error::*;
 use log::{warn, error, info, debug};
+use crate::users;
+use crate::xattrs;
+use crate::sparse::{self, DataReader};
+use crate::chunker::{self, Chunker, ChunkingStrategy};
+use crate::compression::Compression;
+use crate::archive_log::ArchiveLog;
 
 
 pub fn execute(args: &ArgMatches) {
 	let mut config = Config::default();
 	let args_keyfile = args.value_of("keyfile").expect("internal error");
-	let args_backend = args.value_of("backend").expect("internal error");
+	let args_backends = args.values_of("backend").expect("internal error");
 	let backup_name = args.value_of("NAME").expect("internal error");
 	let target_directory = Path::new(args.value_of("PATH").expect("internal error"));
-	let exclude_paths: Vec<&str> = args.values_of("exclude").unwrap_or(clap::Values::default()).collect();
 
+	config.excludes = args.values_of("exclude").unwrap_or(clap::Values::default()).map(|path| path.to_owned()).collect();
 	config.dereference_symlinks = args.is_present("dereference");
 	config.one_file_system = args.is_present("one-file-system");
+	config.xattr_namespaces = match xattrs::parse_namespaces(args.value_of("xattr-namespaces").unwrap_or(xattrs::DEFAULT_NAMESPACES)) {
+		Some(namespaces) => namespaces,
+		None => {
+			error!("Invalid --xattr-namespaces.  Expected a comma separated list of: {}", xattrs::NAMESPACES.join(", "));
+			return;
+		}
+	};
+	config.chunking = match ChunkingStrategy::parse(args.value_of("chunker").unwrap_or("fixed"), args.value_of("chunk-size")) {
+		Some(chunking) => chunking,
+		None => {
+			error!("Invalid --chunker or --chunk-size.  fixed takes SIZE, fastcdc takes MIN,AVG,MAX where AVG is a power of two and MIN < AVG < MAX.");
+			return;
+		}
+	};
+	config.compression = Compression::from_name(args.value_of("compression").unwrap_or("none")).expect("internal error");
 
 	let keystore = match KeyStore::load_from_path(args_keyfile) {
 		Ok(keystore) => keystore,
@@ -34,17 +54,38 @@ pub fn execute(args: &ArgMatches) {
 		}
 	};
 
-	let mut backend = match backend::backend_from_backend_path(args_backend) {
-		Ok(backend) => backend,
+	let on_backend_failure = match args.value_of("on-backend-failure") {
+		Some("warn") => OnBackendFailure::Warn,
+		_ => OnBackendFailure::Fail,
+	};
+	let mut backend = MirrorBackend::new(on_backend_failure);
+
+	for args_backend in args_backends {
+		match backend::backend_from_backend_path(args_backend) {
+			Ok(opened) => backend.add(args_backend, opened),
+			Err(err) if on_backend_failure == OnBackendFailure::Warn => {
+				warn!("Unable to load backend '{}', continuing without it: {}", args_backend, err);
+				backend.add_failed(args_backend, &err);
+			},
+			Err(err) => {
+				error!("Unable to load backend '{}': {}", args_backend, err);
+				return;
+			}
+		}
+	}
+
+	// Open the log up front, so a corrupt log is noticed before spending time on the backup
+	let mut archive_log = match args.value_of("archive-log").map(|path| ArchiveLog::open(path, &keystore)).transpose() {
+		Ok(archive_log) => archive_log,
 		Err(err) => {
-			error!("Unable to load backend: {}", err);
+			error!("Unable to open the archive log: {}", err);
 			return;
 		}
 	};
 
 	// Build archive
 	let archive = {
-		let mut builder = match ArchiveBuilder::new(config, &target_directory, &mut *backend, &keystore) {
+		let mut builder = match ArchiveBuilder::new(config, &target_directory, &mut backend, &keystore) {
 			Ok(builder) => builder,
 			Err(err) => {
 				error!("There was a problem initializing the archive builder: {}", err);
@@ -52,11 +93,6 @@ pub fn execute(args: &ArgMatches) {
 			},
 		};
 
-		// Add user specified excludes
-		for path in exclude_paths {
-			builder.path_ignore_list.insert(PathBuf::from(path));
-		}
-
 		info!("Gathering list of files...");
 		match builder.walk() {
 			Ok(_) => (),
@@ -90,21 +126,40 @@ pub fn execute(args: &ArgMatches) {
 	};
 
 	info!("Writing archive...");
-	let (archive_id, encrypted_archive_name, encrypted_archive) = match archive.encrypt(&keystore) {
+	let (archive_id, encrypted_archive_name, encrypted_blocklist, encrypted_archive) = match archive.encrypt(&keystore) {
 		Ok(x) => x,
 		Err(err) => {
 			error!("There was a problem encrypting the backup: {}", err);
 			return;
 		}
 	};
-	match backend.store_archive(&archive_id, &encrypted_archive_name, &encrypted_archive) {
+	match backend.store_archive(&archive_id, &encrypted_archive_name, &encrypted_blocklist, &encrypted_archive) {
 		Ok(_) => (),
 		Err(err) => {
 			error!("There was a problem storing the archive: {}", err);
 			return;
 		}
 	}
+
+	if let Some(ref mut archive_log) = archive_log {
+		if let Err(err) = archive_log.append(&keystore, &archive_id, &encrypted_archive_name) {
+			error!("The backup was stored, but could not be added to the archive log: {}", err);
+			return;
+		}
+	}
+
 	info!("Backup created successfully");
+
+	let report = backend.report();
+
+	if report.len() > 1 {
+		for (path, err) in report {
+			match err {
+				Some(err) => warn!("  FAILED  {}: {}", path, err),
+				None => info!("  OK      {}", path),
+			}
+		}
+	}
 }
 
 
@@ -116,6 +171,18 @@ struct Config {
 
 	/// If true, we will skip all files/directories that reside on other filesystems.
 	one_file_system: bool,
+
+	/// User specified paths to exclude.
+	excludes: Vec<String>,
+
+	/// Extended attributes in these namespaces will be backed up.
+	xattr_namespaces: Vec<String>,
+
+	/// How files are split into blocks.
+	chunking: ChunkingStrategy,
+
+	/// Codec used to compress blocks.
+	compression: Compression,
 }
 
 /// Used to uniquely identify a file during backup creation, so we can
@@ -143,11 +210,36 @@ struct ArchiveBuilderFile {
 	file: archive::File,
 	missing: bool,
 	canonical_path: Option<PathBuf>,
+	/// Change time and inode number, used along with mtime and size to decide whether the mtime cache can be trusted.
+	/// Unlike mtime, ctime can't be set by tools like `touch -r`, and replacing a file with a new one changes its inode.
+	ctime: i64,
+	ctime_nsec: i64,
+	inode: u64,
+}
+
+/// A file's blocks, and the holes skipped while reading it.
+type FileBlocks = (Vec<BlockId>, Vec<Hole>);
+
+/// Everything read_file needs to split files into blocks and store them.
+struct FileReader<'a> {
+	chunker: Chunker,
+	compression: Compression,
+	keystore: &'a KeyStore,
+	backend: &'a mut dyn Backend,
+	/// Bytes of the files read so far, out of total_size, for progress reports
+	progress: u64,
+	total_size: u64,
+	/// Number of bytes uploaded to the backend (blocks which didn't already exist)
+	new_bytes: &'a mut u64,
 }
 
 struct ArchiveBuilder<'a> {
 	config: Config,
 	base_path: PathBuf,
+	/// When we started building the archive
+	created: i64,
+	/// Number of bytes uploaded to the backend (blocks which didn't already exist)
+	new_bytes: u64,
 	hardlink_map: HashMap<FileIdentifier, HardLink>,
 	last_hardlink_id: u64,
 	total_size: u64,
@@ -184,13 +276,18 @@ impl<'a> ArchiveBuilder<'a> {
 		// TODO: Make it possible to disable these with a command line flag
 		path_ignore_list.insert(PathBuf::from("/proc"));
 		path_ignore_list.insert(PathBuf::from("/sys"));
-		path_ignore_list.insert(PathBuf::from("/dev"));
 		path_ignore_list.insert(PathBuf::from("/run"));
 		path_ignore_list.insert(PathBuf::from("/tmp"));
 
+		for path in &config.excludes {
+			path_ignore_list.insert(PathBuf::from(path));
+		}
+
 		Ok(ArchiveBuilder {
 			config,
 			base_path,
+			created: time::get_time().sec,
+			new_bytes: 0,
 			hardlink_map: HashMap::new(),
 			total_size: 0,
 			last_hardlink_id: 0,
@@ -213,6 +310,29 @@ impl<'a> ArchiveBuilder<'a> {
 			blocks TEXT NOT NULL
 		)", rusqlite::NO_PARAMS)?;
 
+		// Older cache databases don't have the ctime and inode columns.  Their entries won't match anything, so files get read once more.
+		if db.prepare("SELECT ctime, ctime_nsec, inode FROM mtime_cache LIMIT 0").is_err() {
+			db.execute("ALTER TABLE mtime_cache ADD COLUMN ctime INTEGER NOT NULL DEFAULT -1", rusqlite::NO_PARAMS)?;
+			db.execute("ALTER TABLE mtime_cache ADD COLUMN ctime_nsec INTEGER NOT NULL DEFAULT -1", rusqlite::NO_PARAMS)?;
+			db.execute("ALTER TABLE mtime_cache ADD COLUMN inode INTEGER NOT NULL DEFAULT -1", rusqlite::NO_PARAMS)?;
+		}
+
+		// Entries from before holes were recorded are still correct; their blocks cover the whole file.
+		if db.prepare("SELECT holes FROM mtime_cache LIMIT 0").is_err() {
+			db.execute("ALTER TABLE mtime_cache ADD COLUMN holes TEXT NOT NULL DEFAULT '[]'", rusqlite::NO_PARAMS)?;
+		}
+
+		// Entries from before chunking was configurable were split into fixed 1MB blocks.
+		if db.prepare("SELECT chunking FROM mtime_cache LIMIT 0").is_err() {
+			let legacy = serde_json::to_string(&chunker::LEGACY_STRATEGY).expect("internal error");
+			db.execute(&format!("ALTER TABLE mtime_cache ADD COLUMN chunking TEXT NOT NULL DEFAULT '{}'", legacy), rusqlite::NO_PARAMS)?;
+		}
+
+		// And their blocks were never compressed.
+		if db.prepare("SELECT compression FROM mtime_cache LIMIT 0").is_err() {
+			db.execute("ALTER TABLE mtime_cache ADD COLUMN compression TEXT NOT NULL DEFAULT 'none'", rusqlite::NO_PARAMS)?;
+		}
+
 		db.execute("CREATE INDEX IF NOT EXISTS idx_mtime_cache_path_mtime_size ON mtime_cache (path, mtime, mtime_nsec, size);", rusqlite::NO_PARAMS)?;
 		db.execute("CREATE UNIQUE INDEX IF NOT EXISTS idx_mtime_cache_path ON mtime_cache (path);", rusqlite::NO_PARAMS)?;
 
@@ -252,8 +372,28 @@ impl<'a> ArchiveBuilder<'a> {
 		let files: Vec<archive::File> = self.files.iter().map(|file| file.file.clone()).collect();
 
 		Ok(Archive {
-			version: 0x00000001,
+			version: archive::ARCHIVE_VERSION,
 			name: name.to_owned(),
+			created: Some(self.created),
+			finished: Some(time::get_time().sec),
+			hostname: hostname(),
+			username: users::user_name(unsafe { libc::geteuid() }),
+			preserve_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+			options: Some(CreateOptions {
+				dereference: self.config.dereference_symlinks,
+				one_file_system: self.config.one_file_system,
+				excludes: self.config.excludes.clone(),
+				xattr_namespaces: self.config.xattr_namespaces.clone(),
+				chunking: Some(self.config.chunking),
+				compression: self.config.compression,
+			}),
+			stats: Some(ArchiveStats {
+				file_count: files.len() as u64,
+				total_size: files.iter().map(|file| file.size).sum(),
+				new_bytes: self.new_bytes,
+			}),
+			user_names: files.iter().map(|file| file.uid).collect::<BTreeSet<_>>().into_iter().filter_map(|uid| users::user_name(uid).map(|name| (uid, name))).collect(),
+			group_names: files.iter().map(|file| file.gid).collect::<BTreeSet<_>>().into_iter().filter_map(|gid| users::group_name(gid).map(|name| (gid, name))).collect(),
 			original_path: self.base_path.canonicalize()?.to_string_lossy().to_string(),
 			files: files,
 		})
@@ -329,11 +469,25 @@ impl<'a> ArchiveBuilder<'a> {
 			return None;
 		}
 
-		// Skip anything that isn't a symlink, regular file, or directory.
-		if symlink_path.is_none() && !metadata.is_file() && !metadata.is_dir() {
-			warn!("Skipping '{}' because it is not a symlink, directory, or regular file.", path.as_ref().display());
+		// Skip anything that isn't a symlink, regular file, directory, FIFO, or device node.
+		let file_type = metadata.file_type();
+		let special = if symlink_path.is_some() || metadata.is_file() || metadata.is_dir() {
+			None
+		} else if file_type.is_fifo() {
+			Some(SpecialFile::Fifo)
+		} else if file_type.is_block_device() {
+			let (major, minor) = SpecialFile::split_rdev(metadata.rdev());
+			Some(SpecialFile::BlockDevice { major, minor })
+		} else if file_type.is_char_device() {
+			let (major, minor) = SpecialFile::split_rdev(metadata.rdev());
+			Some(SpecialFile::CharDevice { major, minor })
+		} else if file_type.is_socket() {
+			warn!("Skipping '{}' because it is a socket.  Sockets are recreated by the programs that listen on them.", path.as_ref().display());
 			return None;
-		}
+		} else {
+			warn!("Skipping '{}' because it is not a symlink, directory, regular file, FIFO, or device.", path.as_ref().display());
+			return None;
+		};
 
 		let filesize = if symlink_path.is_none() && metadata.is_file() {
 			metadata.len()
@@ -361,6 +515,14 @@ impl<'a> ArchiveBuilder<'a> {
 			}
 		};
 
+		let xattrs = match xattrs::read(path.as_ref(), symlink_path.is_none(), &self.config.xattr_namespaces) {
+			Ok(xattrs) => xattrs,
+			Err(err) => {
+				warn!("Unable to read extended attributes for '{}'.  They will not be included in the archive.  The following error was received: {}", path.as_ref().display(), err);
+				BTreeMap::new()
+			},
+		};
+
 		// Handle hardlinks
 		let hardlink_id = if metadata.nlink() > 1 && !metadata.is_dir() {
 			let key = FileIdentifier {
@@ -396,13 +558,21 @@ impl<'a> ArchiveBuilder<'a> {
 				mode: metadata.mode(),
 				mtime: metadata.mtime(),
 				mtime_nsec: metadata.mtime_nsec(),
+				atime: Some(metadata.atime()),
+				atime_nsec: metadata.atime_nsec(),
 				uid: metadata.uid(),
 				gid: metadata.gid(),
 				size: filesize,
 				blocks: Vec::new(),
+				holes: Vec::new(),
+				xattrs,
+				special,
 			},
 			missing: false,
 			canonical_path: canonical_path,
+			ctime: metadata.ctime(),
+			ctime_nsec: metadata.ctime_nsec(),
+			inode: metadata.ino(),
 		})
 	}
 
@@ -512,22 +682,33 @@ impl<'a> ArchiveBuilder<'a> {
 	}
 
 	fn read_files(&mut self) -> Result<()> {
-		let mut progress = 0;
 		let cache_db = self.open_cache_db()?;
+		let mut reader = FileReader {
+			chunker: Chunker::new(self.config.chunking, self.keystore),
+			compression: self.config.compression,
+			keystore: self.keystore,
+			backend: &mut *self.backend,
+			progress: 0,
+			total_size: self.total_size,
+			new_bytes: &mut self.new_bytes,
+		};
 
 		for file in &mut self.files {
-			if file.file.is_dir || file.file.symlink.is_some() {
+			if file.file.is_dir || file.file.symlink.is_some() || file.file.special.is_some() {
 				continue;
 			}
 
 			info!("Reading file: {}", file.file.path);
-			match read_file(file, &self.base_path, &cache_db, self.keystore, self.backend, progress, self.total_size)? {
-				Some(blocks) => file.file.blocks.extend(blocks),
+			match read_file(file, &self.base_path, &cache_db, &mut reader)? {
+				Some((blocks, holes)) => {
+					file.file.blocks.extend(blocks);
+					file.file.holes = holes;
+				},
 				None => file.missing = true,
 			};
 
-			progress += file.file.size;
-			info!("Progress: {}MB of {}MB", progress / (1024*1024), self.total_size / (1024*1024));
+			reader.progress += file.file.size;
+			info!("Progress: {}MB of {}MB", reader.progress / (1024*1024), reader.total_size / (1024*1024));
 		}
 
 		self.files.retain(|ref file| !file.missing);
@@ -537,7 +718,7 @@ impl<'a> ArchiveBuilder<'a> {
 }
 
 
-fn read_file<P: AsRef<Path>>(file: &mut ArchiveBuilderFile, base_path: P, cache_db: &rusqlite::Connection, keystore: &KeyStore, backend: &mut dyn Backend, progress: u64, total_size: u64) -> Result<Option<Vec<BlockId>>> {
+fn read_file<P: AsRef<Path>>(file: &mut ArchiveBuilderFile, base_path: P, cache_db: &rusqlite::Connection, reader: &mut FileReader) -> Result<Option<FileBlocks>> {
 	let path = base_path.as_ref().join(&file.file.path);
 	let canonical_path = match file.canonical_path.clone() {
 		Some(canonical_path) => canonical_path,
@@ -554,22 +735,25 @@ fn read_file<P: AsRef<Path>>(file: &mut ArchiveBuilderFile, base_path: P, cache_
 		}
 	};
 
-	// Check to see if we have this file in the cache
-	let result = cache_db.query_row("SELECT blocks FROM mtime_cache WHERE path=? AND mtime=? AND mtime_nsec=? AND size=?", &[&canonical_path_str.to_owned() as &dyn ToSql, &file.file.mtime, &file.file.mtime_nsec, &(file.file.size as i64)], |row| {
-		row.get(0)
+	// Check to see if we have this file in the cache.  Blocks from a different chunking strategy or codec can't be mixed into the archive.
+	let chunking_str = serde_json::to_string(&reader.chunker.strategy()).expect("internal error");
+	let compression_str = reader.compression.name().to_owned();
+	let result = cache_db.query_row("SELECT blocks, holes FROM mtime_cache WHERE path=? AND mtime=? AND mtime_nsec=? AND size=? AND ctime=? AND ctime_nsec=? AND inode=? AND chunking=? AND compression=?", &[&canonical_path_str.to_owned() as &dyn ToSql, &file.file.mtime, &file.file.mtime_nsec, &(file.file.size as i64), &file.ctime, &file.ctime_nsec, &(file.inode as i64), &chunking_str, &compression_str], |row| {
+		Ok((row.get(0)?, row.get(1)?))
 	});
 
 	match result {
-		Ok(blocks_str) => {
+		Ok((blocks_str, holes_str)) => {
 			// The file is cached, but are all the blocks available in the current block store?
 			let blocks_str: String = blocks_str;
+			let holes_str: String = holes_str;
 
-			match serde_json::from_str::<Vec<BlockId>>(&blocks_str) {
-				Ok(blocks) => {
+			match (serde_json::from_str::<Vec<BlockId>>(&blocks_str), serde_json::from_str::<Vec<Hole>>(&holes_str)) {
+				(Ok(blocks), Ok(holes)) => {
 					let mut all_blocks_exist = true;
 
 					for block in &blocks {
-						if !backend.block_exists(block)? {
+						if !reader.backend.block_exists(block)? {
 							all_blocks_exist = false;
 							break;
 						}
@@ -577,10 +761,10 @@ fn read_file<P: AsRef<Path>>(file: &mut ArchiveBuilderFile, base_path: P, cache_
 
 					if all_blocks_exist {
 						debug!("Found in mtime cache.");
-						return Ok(Some(blocks));
+						return Ok(Some((blocks, holes)));
 					}
 				},
-				Err(_) => {
+				_ => {
 					warn!("Bad block id encoding in the cache database.  The cache database might be corrupted.");
 				},
 			}
@@ -597,6 +781,9 @@ fn read_file<P: AsRef<Path>>(file: &mut ArchiveBuilderFile, base_path: P, cache_
 			Ok(metadata) => {
 				file.file.mtime = metadata.mtime();
 				file.file.mtime_nsec = metadata.mtime_nsec();
+				file.ctime = metadata.ctime();
+				file.ctime_nsec = metadata.ctime_nsec();
+				file.inode = metadata.ino();
 				file.file.size = metadata.size();
 				file.file.mode = metadata.mode();
 				file.file.uid = metadata.uid();
@@ -609,9 +796,9 @@ fn read_file<P: AsRef<Path>>(file: &mut ArchiveBuilderFile, base_path: P, cache_
 		};
 
 		// Read file contents
-		let (blocks, should_retry) = read_file_inner(&path, keystore, backend, progress, total_size, file.file.mtime, file.file.mtime_nsec, file.file.size)?;
+		let (blocks, should_retry) = read_file_inner(&path, reader, file)?;
 
-		let blocks = match blocks {
+		let (blocks, holes) = match blocks {
 			Some(blocks) => blocks,
 			None => {
 				// Reading failed.  Should we retry?
@@ -632,16 +819,20 @@ fn read_file<P: AsRef<Path>>(file: &mut ArchiveBuilderFile, base_path: P, cache_
 		};
 
 		let blocks_str = serde_json::to_string(&blocks).expect("internal error");
-		cache_db.execute("INSERT OR REPLACE INTO mtime_cache (path, mtime, mtime_nsec, size, blocks) VALUES (?,?,?,?,?)", &[&canonical_path_str.to_owned() as &dyn ToSql, &file.file.mtime, &file.file.mtime_nsec, &(file.file.size as i64), &blocks_str])?;
+		let holes_str = serde_json::to_string(&holes).expect("internal error");
+		cache_db.execute("INSERT OR REPLACE INTO mtime_cache (path, mtime, mtime_nsec, size, ctime, ctime_nsec, inode, chunking, compression, blocks, holes) VALUES (?,?,?,?,?,?,?,?,?,?,?)", &[&canonical_path_str.to_owned() as &dyn ToSql, &file.file.mtime, &file.file.mtime_nsec, &(file.file.size as i64), &file.ctime, &file.ctime_nsec, &(file.inode as i64), &chunking_str, &compression_str, &blocks_str, &holes_str])?;
 
-		return Ok(Some(blocks));
+		return Ok(Some((blocks, holes)));
 	}
 }
 
 
-// Used by read_file.  read_file checks the cache, etc.  This will actually read the file into blocks.
+// Used by read_file.  read_file checks the cache, etc.  This will actually read the file into blocks, as split by chunker.
+// Holes in sparse files are skipped, and returned alongside the blocks.
 // If any file modifications are detected while reading, this function will return (None, true) to indicate the caller that it should retry (if it wishes).
-fn read_file_inner<P: AsRef<Path>>(path: P, keystore: &KeyStore, backend: &mut dyn Backend, progress: u64, total_size: u64, expected_mtime: i64, expected_mtime_nsec: i64, expected_size: u64) -> Result<(Option<Vec<BlockId>>, bool)> {
+// Modifications are detected by comparing against the metadata in expected.  ctime catches writes that put the mtime back, and the inode a file replaced by another.
+fn read_file_inner<P: AsRef<Path>>(path: P, reader: &mut FileReader, expected: &ArchiveBuilderFile) -> Result<(Option<FileBlocks>, bool)> {
+	let expected_size = expected.file.size;
 	let reader_file = match fs::File::open(&path) {
 		Ok(f) => f,
 		Err(err) => {
@@ -649,15 +840,20 @@ fn read_file_inner<P: AsRef<Path>>(path: P, keystore: &KeyStore, backend: &mut d
 			return Ok((None, false))
 		},
 	};
-	let reader = BufReader::new(&reader_file);
-	let reader_ref = reader.get_ref();
+	let holes = match sparse::find_holes(&reader_file, expected_size) {
+		Ok(holes) => holes,
+		Err(err) => {
+			warn!("Unable to look for holes in '{}': {}.  It will be read as a regular file.", path.as_ref().display(), err);
+			Vec::new()
+		},
+	};
+	let mut chunks = reader.chunker.chunks(DataReader::new(&reader_file, &holes, expected_size));
 	let mut buffer = Vec::<u8>::new();
 	let mut total_read = 0;
 	let mut blocks = Vec::new();
 
 	loop {
-		buffer.clear();
-		match reader_ref.take(1024*1024).read_to_end(&mut buffer) {
+		match chunks.next_chunk(&mut buffer) {
 			Ok(_) => (),
 			Err(err) => {
 				// Problem reading the file.  Restart.
@@ -669,7 +865,7 @@ fn read_file_inner<P: AsRef<Path>>(path: P, keystore: &KeyStore, backend: &mut d
 		// Check for file modification
 		match path.as_ref().metadata() {
 			Ok(metadata) => {
-				if metadata.mtime() != expected_mtime || metadata.mtime_nsec() != expected_mtime_nsec {
+				if metadata.mtime() != expected.file.mtime || metadata.mtime_nsec() != expected.file.mtime_nsec || metadata.ctime() != expected.ctime || metadata.ctime_nsec() != expected.ctime_nsec || metadata.size() != expected_size || metadata.ino() != expected.inode {
 					// The file has been modified.  Restart.
 					return Ok((None, true));
 				}
@@ -684,27 +880,42 @@ fn read_file_inner<P: AsRef<Path>>(path: P, keystore: &KeyStore, backend: &mut d
 			break;
 		}
 
+		let previous_read = total_read;
 		total_read += buffer.len();
 
-		// Encrypt and store block in backend (if it doesn't already exist)
-		let (block_id, encrypted_block) = keystore.encrypt_block(&buffer);
+		// Compress, encrypt and store block in backend (if it doesn't already exist)
+		let (block_id, encrypted_block) = reader.keystore.encrypt_block(&buffer, reader.compression);
 
-		if !backend.block_exists(&block_id)? {
+		if !reader.backend.block_exists(&block_id)? {
 			// Block doesn't exist in backend; store it
-			backend.store_block(&block_id, &encrypted_block)?;
+			reader.backend.store_block(&block_id, &encrypted_block)?;
+			*reader.new_bytes += encrypted_block.0.len() as u64;
 		}
 
 		blocks.push(block_id);
 
-		if (total_read % (64*1024*1024)) == 0 {
-			info!("Progress: {}MB of {}MB", (progress + total_read as u64) / (1024*1024), total_size / (1024*1024));
+		if total_read / (64*1024*1024) != previous_read / (64*1024*1024) {
+			info!("Progress: {}MB of {}MB", (reader.progress + total_read as u64) / (1024*1024), reader.total_size / (1024*1024));
 		}
 	}
 
-	if total_read as u64 != expected_size {
+	if total_read as u64 + sparse::hole_size(&holes) != expected_size {
 		// File was modified
 		return Ok((None, true));
 	}
 
-	Ok((Some(blocks), false))
+	Ok((Some((blocks, holes)), false))
+}
+
+
+fn hostname() -> Option<String> {
+	let mut buffer = [0u8; 256];
+
+	if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) } != 0 {
+		return None;
+	}
+
+	let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+
+	String::from_utf8(buffer[..len].to_vec()).ok()
 }
@@ -0,0 +1,39 @@
+use crate::keystore::KeyStore;
+use crate::backend;
+use clap::ArgMatches;
+use log::{error, info};
+
+
+pub fn execute(args: &ArgMatches) {
+	let backup_name = args.value_of("NAME").expect("internal error");
+	let args_keyfile = args.value_of("keyfile").expect("internal error");
+	let args_backend = args.value_of("backend").expect("internal error");
+
+	let keystore = match KeyStore::load_from_path(args_keyfile) {
+		Ok(keystore) => keystore,
+		Err(err) => {
+			error!("Unable to load keyfile: {}", err);
+			return;
+		}
+	};
+
+	let mut backend = match backend::backend_from_backend_path(args_backend) {
+		Ok(backend) => backend,
+		Err(err) => {
+			error!("Unable to load backend: {}", err);
+			return;
+		}
+	};
+
+	let (archive_id, _) = keystore.encrypt_archive_name(&backup_name);
+
+	match backend.delete_archive(&archive_id) {
+		Ok(_) => (),
+		Err(err) => {
+			error!("There was a problem deleting the backup: {}", err);
+			return;
+		}
+	}
+
+	info!("Backup deleted.  Run gc to remove any blocks that are no longer referenced.");
+}
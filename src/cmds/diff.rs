@@ -1,9 +1,8 @@
 use clap::ArgMatches;
 use log::{error, warn};
 use crate::keystore::KeyStore;
-use crate::backend::{self, Backend};
+use crate::backend;
 use crate::archive::{Archive, File};
-use crate::error::Result;
 use std::collections::{HashMap, HashSet};
 
 
@@ -29,7 +28,7 @@ pub fn execute(args: &ArgMatches) {
 		}
 	};
 
-	let mut archive1 = match fetch_and_decrypt_archive(backup1_name, &keystore, &mut *backend) {
+	let mut archive1 = match Archive::fetch(backup1_name, &keystore, &mut *backend) {
 		Ok(archive) => archive,
 		Err(err) => {
 			error!("{}", err);
@@ -37,7 +36,7 @@ pub fn execute(args: &ArgMatches) {
 		}
 	};
 
-	let mut archive2 = match fetch_and_decrypt_archive(backup2_name, &keystore, &mut *backend) {
+	let mut archive2 = match Archive::fetch(backup2_name, &keystore, &mut *backend) {
 		Ok(archive) => archive,
 		Err(err) => {
 			error!("{}", err);
@@ -45,11 +44,6 @@ pub fn execute(args: &ArgMatches) {
 		}
 	};
 
-	if archive1.version != 0x00000001 || archive2.version != 0x00000001 {
-		error!("Unsupported archive version");
-		return;
-	}
-
 	if archive1.original_path != archive2.original_path {
 		warn!("The original paths for the two archives differ.  This may or may not be important depending on what you're comparing.");
 	}
@@ -57,12 +51,11 @@ pub fn execute(args: &ArgMatches) {
 	// TODO: Need to handle hardlinks properly?
 	// TODO: For now, we know that preserve stores the list of blocks for all files, even those with hardlink_ids, so we can ignore the hardlink_id field.
 	// TODO: Basically this means that we'll still detect differences in file contents, but we can't report if hardlinks themselves have changed.
-	for file in &mut archive1.files {
-		file.hardlink_id = None;
-	}
-
-	for file in &mut archive2.files {
+	// Access times change whenever a file is read (including by preserve itself), so they aren't worth reporting.
+	for file in archive1.files.iter_mut().chain(archive2.files.iter_mut()) {
 		file.hardlink_id = None;
+		file.atime = None;
+		file.atime_nsec = 0;
 	}
 
 	let archive1_hashmap: HashMap<&String, &File> = archive1.files.iter().map(|file| (&file.path, file)).collect();
@@ -96,9 +89,3 @@ pub fn execute(args: &ArgMatches) {
 		});
 }
 
-
-fn fetch_and_decrypt_archive(name: &str, keystore: &KeyStore, backend: &mut dyn Backend) -> Result<Archive> {
-	let (archive_id, _) = keystore.encrypt_archive_name(&name);
-	let encrypted_archive = backend.fetch_archive(&archive_id)?;
-	Archive::decrypt(&archive_id, &encrypted_archive, &keystore)
-}
\ No newline at end of file
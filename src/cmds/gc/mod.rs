@@ -0,0 +1,108 @@
+use crate::keystore::{KeyStore, BlockId};
+use std::collections::HashSet;
+use crate::backend::{self, Backend};
+use crate::archive::Archive;
+use clap::ArgMatches;
+use crate::error::*;
+use log::{error, info};
+
+
+pub fn execute(args: &ArgMatches) {
+	let args_keyfile = args.value_of("keyfile").expect("internal error");
+	let args_backend = args.value_of("backend").expect("internal error");
+	let dry_run = args.is_present("dry-run");
+
+	let keystore = match KeyStore::load_from_path(args_keyfile) {
+		Ok(keystore) => keystore,
+		Err(err) => {
+			error!("Unable to load keyfile: {}", err);
+			return;
+		}
+	};
+
+	let mut backend = match backend::backend_from_backend_path(args_backend) {
+		Ok(backend) => backend,
+		Err(err) => {
+			error!("Unable to load backend: {}", err);
+			return;
+		}
+	};
+
+	info!("Reading archives...");
+	let live_blocks = match find_live_blocks(&keystore, &mut *backend) {
+		Ok(blocks) => blocks,
+		Err(err) => {
+			error!("There was a problem reading the archives.  Nothing will be removed.  {}", err);
+			return;
+		}
+	};
+
+	let stored_blocks = match backend.list_blocks() {
+		Ok(blocks) => blocks,
+		Err(err) => {
+			error!("There was a problem listing the blocks: {}", err);
+			return;
+		}
+	};
+
+	let orphaned_blocks: Vec<(BlockId, u64)> = stored_blocks.into_iter().filter(|(block_id, _)| !live_blocks.contains(block_id)).collect();
+	let unreferenced_size: u64 = orphaned_blocks.iter().map(|(_, size)| size).sum();
+
+	if dry_run {
+		info!("{} unreferenced blocks, totalling {}MB ({} bytes), would be removed", orphaned_blocks.len(), unreferenced_size / (1024*1024), unreferenced_size);
+
+		if !orphaned_blocks.is_empty() {
+			info!("Space used by packed blocks is only freed once most of their pack is unused");
+		}
+
+		return;
+	}
+
+	let mut removed = 0;
+	let mut removed_size = 0;
+
+	for (block_id, size) in &orphaned_blocks {
+		match backend.delete_block(block_id) {
+			Ok(_) => {
+				removed += 1;
+				removed_size += size;
+			},
+			Err(err) => error!("A problem occured while removing the block '{}': {}", block_id.to_string(), err),
+		}
+	}
+
+	if let Err(err) = backend.flush() {
+		error!("A problem occured while compacting the backend: {}", err);
+	}
+
+	info!("Removed {} unreferenced blocks, totalling {}MB ({} bytes)", removed, removed_size / (1024*1024), removed_size);
+
+	if removed > 0 {
+		info!("Space used by packed blocks is only freed once most of their pack is unused");
+	}
+}
+
+
+/// Reads every archive's blocklist and returns the set of all blocks they reference.
+/// Archives stored without a blocklist are decrypted instead.
+/// Any archive that can't be read is an error, since we would otherwise consider its blocks unreferenced.
+fn find_live_blocks(keystore: &KeyStore, backend: &mut dyn Backend) -> Result<HashSet<BlockId>> {
+	let mut live_blocks = HashSet::new();
+
+	for (archive_id, encrypted_archive_name) in backend.list_archives()? {
+		let archive_name = keystore.decrypt_archive_name(&archive_id, &encrypted_archive_name)?;
+		info!("Reading archive: {}", archive_name);
+
+		match backend.fetch_archive_blocklist(&archive_id)? {
+			Some(encrypted_blocklist) => {
+				live_blocks.extend(keystore.decrypt_archive_blocklist(&archive_id, &encrypted_blocklist)?);
+			},
+			None => {
+				let encrypted_archive = backend.fetch_archive(&archive_id)?;
+				live_blocks.extend(Archive::decrypt(&archive_id, &encrypted_archive, keystore)?.blocklist());
+			},
+		}
+	}
+
+	Ok(live_blocks)
+}
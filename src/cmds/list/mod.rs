@@ -1,12 +1,46 @@
-use crate::keystore::KeyStore;
-use crate::backend;
+use crate::keystore::{KeyStore, ArchiveId};
+use crate::backend::{self, Backend};
+use crate::archive::Archive;
+use crate::error::*;
 use clap::ArgMatches;
+use globset::Glob;
 use log::{error, warn};
+use serde_derive::Serialize;
 
 
 pub fn execute(args: &ArgMatches) {
 	let args_keyfile = args.value_of("keyfile").expect("internal error");
 	let args_backend = args.value_of("backend").expect("internal error");
+	let json = args.is_present("json");
+	let long = args.is_present("long") || json;
+	let sort_by_time = args.value_of("sort") == Some("time");
+
+	let name_filter = match args.value_of("name").map(Glob::new) {
+		Some(Ok(glob)) => Some(glob.compile_matcher()),
+		Some(Err(err)) => {
+			error!("Invalid --name pattern: {}", err);
+			return;
+		},
+		None => None,
+	};
+
+	let after = match args.value_of("after").map(parse_date) {
+		Some(Some(date)) => Some(date),
+		Some(None) => {
+			error!("Invalid --after date.  Expected YYYY-MM-DD or 'YYYY-MM-DD HH:MM:SS'.");
+			return;
+		},
+		None => None,
+	};
+
+	let before = match args.value_of("before").map(parse_date) {
+		Some(Some(date)) => Some(date),
+		Some(None) => {
+			error!("Invalid --before date.  Expected YYYY-MM-DD or 'YYYY-MM-DD HH:MM:SS'.");
+			return;
+		},
+		None => None,
+	};
 
 	let keystore = match KeyStore::load_from_path(args_keyfile) {
 		Ok(keystore) => keystore,
@@ -32,7 +66,8 @@ pub fn execute(args: &ArgMatches) {
 		}
 	};
 
-	// TODO: Push into a vec, sort alphabetically, and then print
+	let mut entries = Vec::new();
+
 	for (archive_id, encrypted_archive_name) in &encrypted_archive_names {
 		let archive_name = match keystore.decrypt_archive_name(archive_id, encrypted_archive_name) {
 			Ok(name) => name,
@@ -42,10 +77,115 @@ pub fn execute(args: &ArgMatches) {
 			}
 		};
 
-		println!("{}", archive_name);
+		if let Some(ref name_filter) = name_filter {
+			if !name_filter.is_match(&archive_name) {
+				continue;
+			}
+		}
+
+		let mut entry = ListEntry {
+			name: archive_name,
+			id: archive_id.to_string(),
+			..Default::default()
+		};
+
+		// Archive metadata is only needed for the long format and date filtering, and fetching it is expensive.
+		if long || after.is_some() || before.is_some() {
+			match fetch_details(&mut entry, archive_id, &keystore, &mut *backend) {
+				Ok(_) => (),
+				Err(err) => {
+					warn!("Could not read the backup '{}': {}", entry.name, err);
+					continue;
+				}
+			}
+		}
+
+		if after.is_some() || before.is_some() {
+			let created = match entry.created {
+				Some(created) => created,
+				None => continue,
+			};
+
+			if after.is_some_and(|after| created < after) || before.is_some_and(|before| created >= before) {
+				continue;
+			}
+		}
+
+		entries.push(entry);
 	}
 
-	if encrypted_archive_names.is_empty() {
+	if sort_by_time {
+		entries.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.name.cmp(&b.name)));
+	} else {
+		entries.sort_by(|a, b| a.name.cmp(&b.name));
+	}
+
+	if json {
+		println!("{}", serde_json::to_string_pretty(&entries).expect("internal error"));
+		return;
+	}
+
+	for entry in &entries {
+		if long {
+			let created = match entry.created {
+				Some(created) => format_time(created),
+				None => "-".to_string(),
+			};
+
+			println!("{:19}  {:>8} files  {:>14} bytes  {}  ({})", created, entry.file_count.unwrap_or(0), entry.total_size.unwrap_or(0), entry.name, entry.original_path.as_ref().map_or("", |path| path));
+		} else {
+			println!("{}", entry.name);
+		}
+	}
+
+	if entries.is_empty() {
 		println!("No archives found");
 	}
 }
+
+
+#[derive(Serialize, Default)]
+struct ListEntry {
+	name: String,
+	id: String,
+	created: Option<i64>,
+	finished: Option<i64>,
+	hostname: Option<String>,
+	username: Option<String>,
+	original_path: Option<String>,
+	file_count: Option<u64>,
+	total_size: Option<u64>,
+}
+
+
+fn fetch_details(entry: &mut ListEntry, archive_id: &ArchiveId, keystore: &KeyStore, backend: &mut dyn Backend) -> Result<()> {
+	let encrypted_archive = backend.fetch_archive(archive_id)?;
+	let archive = Archive::decrypt(archive_id, &encrypted_archive, keystore)?;
+
+	entry.created = archive.created;
+	entry.finished = archive.finished;
+	entry.hostname = archive.hostname;
+	entry.username = archive.username;
+	entry.original_path = Some(archive.original_path);
+	// Older archives don't have stats, but they're easy to compute
+	entry.file_count = Some(archive.files.len() as u64);
+	entry.total_size = Some(archive.files.iter().map(|file| file.size).sum());
+
+	Ok(())
+}
+
+
+/// Parse a local date ("YYYY-MM-DD" or "YYYY-MM-DD HH:MM:SS") into seconds since the Unix epoch.
+fn parse_date(s: &str) -> Option<i64> {
+	let tm = time::strptime(s, "%Y-%m-%d %H:%M:%S").or_else(|_| time::strptime(s, "%Y-%m-%d")).ok()?;
+	// strptime gives us a UTC time, so adjust for the local timezone
+	let utc = tm.to_timespec().sec;
+	let offset = time::at(time::Timespec::new(utc, 0)).tm_utcoff as i64;
+
+	Some(utc - offset)
+}
+
+
+fn format_time(timestamp: i64) -> String {
+	time::strftime("%Y-%m-%d %H:%M:%S", &time::at(time::Timespec::new(timestamp, 0))).expect("internal error")
+}
@@ -0,0 +1,186 @@
+use crate::keystore::KeyStore;
+use crate::backend;
+use crate::archive::{Archive, File, SpecialFile};
+use clap::ArgMatches;
+use globset::Glob;
+use log::error;
+use serde_derive::Serialize;
+use std::path::Path;
+
+
+pub fn execute(args: &ArgMatches) {
+	let args_keyfile = args.value_of("keyfile").expect("internal error");
+	let args_backend = args.value_of("backend").expect("internal error");
+	let backup_name = args.value_of("NAME").expect("internal error");
+	let prefix = args.value_of("PATH").unwrap_or("").trim_matches('/');
+	let prefix = if prefix == "." { "" } else { prefix };
+	let recursive = args.is_present("recursive");
+	let json = args.is_present("json");
+
+	let glob = match args.value_of("glob").map(Glob::new) {
+		Some(Ok(glob)) => Some(glob.compile_matcher()),
+		Some(Err(err)) => {
+			error!("Invalid --glob pattern: {}", err);
+			return;
+		},
+		None => None,
+	};
+
+	let keystore = match KeyStore::load_from_path(args_keyfile) {
+		Ok(keystore) => keystore,
+		Err(err) => {
+			error!("Unable to load keyfile: {}", err);
+			return;
+		}
+	};
+
+	let mut backend = match backend::backend_from_backend_path(args_backend) {
+		Ok(backend) => backend,
+		Err(err) => {
+			error!("Unable to load backend: {}", err);
+			return;
+		}
+	};
+
+	let archive = match Archive::fetch(backup_name, &keystore, &mut *backend) {
+		Ok(archive) => archive,
+		Err(err) => {
+			error!("There was a problem fetching the backup: {}", err);
+			return;
+		}
+	};
+
+	// If the prefix names something other than a directory, just list that entry (like ls does).
+	let prefix_is_file = archive.files.iter().any(|file| file.path == prefix && (!file.is_dir || file.symlink.is_some()));
+
+	if !prefix.is_empty() && !archive.files.iter().any(|file| file.path == prefix) {
+		error!("'{}' does not exist in the backup", prefix);
+		return;
+	}
+
+	let mut entries: Vec<&File> = archive.files.iter().filter(|file| {
+		let path = Path::new(&file.path);
+
+		let selected = if prefix_is_file {
+			file.path == prefix
+		} else if recursive {
+			prefix.is_empty() || (path.starts_with(prefix) && file.path != prefix)
+		} else {
+			path.parent() == Some(Path::new(prefix))
+		};
+
+		selected && glob.as_ref().is_none_or(|glob| glob.is_match(&file.path))
+	}).collect();
+
+	entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+	if json {
+		let entries: Vec<LsEntry> = entries.iter().map(|file| LsEntry::from(*file)).collect();
+		println!("{}", serde_json::to_string_pretty(&entries).expect("internal error"));
+		return;
+	}
+
+	for file in entries {
+		println!("{}", format_entry(file));
+	}
+}
+
+
+#[derive(Serialize)]
+struct LsEntry<'a> {
+	path: &'a str,
+	#[serde(rename = "type")]
+	file_type: &'static str,
+	mode: u32,
+	uid: u32,
+	gid: u32,
+	size: u64,
+	mtime: i64,
+	mtime_nsec: i64,
+	symlink: Option<&'a str>,
+	hardlink_id: Option<u64>,
+	special: Option<SpecialFile>,
+}
+
+impl<'a> From<&'a File> for LsEntry<'a> {
+	fn from(file: &'a File) -> LsEntry<'a> {
+		let file_type = match (file.special, file.symlink.is_some(), file.is_dir) {
+			(Some(SpecialFile::Fifo), _, _) => "fifo",
+			(Some(SpecialFile::BlockDevice { .. }), _, _) => "block_device",
+			(Some(SpecialFile::CharDevice { .. }), _, _) => "char_device",
+			(None, true, _) => "symlink",
+			(None, false, true) => "directory",
+			(None, false, false) => "file",
+		};
+
+		LsEntry {
+			path: &file.path,
+			file_type,
+			mode: file.mode,
+			uid: file.uid,
+			gid: file.gid,
+			size: file.size,
+			mtime: file.mtime,
+			mtime_nsec: file.mtime_nsec,
+			symlink: file.symlink.as_deref(),
+			hardlink_id: file.hardlink_id,
+			special: file.special,
+		}
+	}
+}
+
+
+/// Format a File like a line of `ls -l`: mode, uid, gid, size, mtime, path, symlink target and hardlink group.
+fn format_entry(file: &File) -> String {
+	let mtime = time::strftime("%Y-%m-%d %H:%M", &time::at(time::Timespec::new(file.mtime, file.mtime_nsec as i32))).expect("internal error");
+	// Like ls, devices show their major and minor numbers instead of a size
+	let size = match file.special {
+		Some(SpecialFile::BlockDevice { major, minor }) | Some(SpecialFile::CharDevice { major, minor }) => format!("{}, {}", major, minor),
+		_ => file.size.to_string(),
+	};
+	let mut line = format!("{} {:>6} {:>6} {:>12} {} {}", format_mode(file), file.uid, file.gid, size, mtime, file.path);
+
+	if let Some(ref symlink) = file.symlink {
+		line.push_str(" -> ");
+		line.push_str(symlink);
+	}
+
+	if let Some(hardlink_id) = file.hardlink_id {
+		line.push_str(&format!("  [hardlink {}]", hardlink_id));
+	}
+
+	line
+}
+
+
+/// Format the file type and permission bits like `ls -l` does (e.g. drwxr-xr-x)
+fn format_mode(file: &File) -> String {
+	let mode = file.mode;
+	let file_type = if let Some(special) = file.special {
+		match special {
+			SpecialFile::Fifo => 'p',
+			SpecialFile::BlockDevice { .. } => 'b',
+			SpecialFile::CharDevice { .. } => 'c',
+		}
+	} else if file.symlink.is_some() {
+		'l'
+	} else if file.is_dir {
+		'd'
+	} else {
+		'-'
+	};
+	let mut chars = vec![file_type];
+
+	for &(bit, c) in &[(0o400, 'r'), (0o200, 'w'), (0o100, 'x'), (0o040, 'r'), (0o020, 'w'), (0o010, 'x'), (0o004, 'r'), (0o002, 'w'), (0o001, 'x')] {
+		chars.push(if mode & bit != 0 { c } else { '-' });
+	}
+
+	// setuid, setgid and sticky replace the corresponding execute bit
+	for &(bit, index, set, unset) in &[(0o4000, 3, 's', 'S'), (0o2000, 6, 's', 'S'), (0o1000, 9, 't', 'T')] {
+		if mode & bit != 0 {
+			chars[index] = if chars[index] == 'x' { set } else { unset };
+		}
+	}
+
+	chars.into_iter().collect()
+}
@@ -3,4 +3,14 @@ pub mod keygen;
 pub mod list;
 pub mod restore;
 pub mod verify;
-pub mod diff;
\ No newline at end of file
+pub mod audit;
+pub mod diff;
+pub mod delete;
+pub mod gc;
+pub mod prune;
+pub mod ls;
+pub mod cat;
+#[cfg(feature = "mount")]
+pub mod mount;
+#[cfg(feature = "http")]
+pub mod serve;
@@ -0,0 +1,430 @@
+use crate::keystore::{KeyStore, BlockId};
+use crate::backend::{self, Backend};
+use crate::archive::{Archive, File, SpecialFile};
+use crate::compression::Compression;
+use crate::error::*;
+use crate::sparse;
+use clap::ArgMatches;
+use fuse::{Filesystem, Request, ReplyEntry, ReplyAttr, ReplyData, ReplyDirectory, FileAttr, FileType};
+use libc::{ENOENT, EIO, EINVAL, EISDIR};
+use log::{error, info, warn};
+use lru::LruCache;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::rc::Rc;
+use time::Timespec;
+
+
+/// How many decrypted blocks to keep in memory.  Blocks are usually 1MB.
+const BLOCK_CACHE_SIZE: usize = 64;
+
+/// Archives never change, so the kernel can cache attributes and lookups for a while.
+const TTL: Timespec = Timespec { sec: 60, nsec: 0 };
+
+const ROOT_INO: u64 = 1;
+
+
+pub fn execute(args: &ArgMatches) {
+	let args_keyfile = args.value_of("keyfile").expect("internal error");
+	let args_backend = args.value_of("backend").expect("internal error");
+	let mountpoint = args.value_of("MOUNTPOINT").expect("internal error");
+
+	let keystore = match KeyStore::load_from_path(args_keyfile) {
+		Ok(keystore) => keystore,
+		Err(err) => {
+			error!("Unable to load keyfile: {}", err);
+			return;
+		}
+	};
+
+	let backend = match backend::backend_from_backend_path(args_backend) {
+		Ok(backend) => backend,
+		Err(err) => {
+			error!("Unable to load backend: {}", err);
+			return;
+		}
+	};
+
+	let filesystem = match ArchiveFilesystem::new(keystore, backend) {
+		Ok(filesystem) => filesystem,
+		Err(err) => {
+			error!("There was a problem listing the archives: {}", err);
+			return;
+		}
+	};
+
+	info!("Mounting backups at '{}'.  Unmount (e.g. fusermount -u) to exit.", mountpoint);
+
+	let options = ["-o", "ro", "-o", "fsname=preserve"].iter().map(OsStr::new).collect::<Vec<&OsStr>>();
+
+	match fuse::mount(filesystem, &mountpoint, &options) {
+		Ok(_) => (),
+		Err(err) => error!("There was a problem mounting the backups: {}", err),
+	}
+}
+
+
+struct Node {
+	parent: u64,
+	/// None for the root and for the top level directory of each archive.
+	file: Option<File>,
+	children: BTreeMap<OsString, u64>,
+	/// For an archive's top level directory: the name of the archive, until the archive has been fetched and its files added as children.
+	unloaded_archive: Option<String>,
+	/// Sizes of the first N blocks of the file, filled in as blocks are read.  Blocks aren't necessarily all the same size,
+	/// so this is what lets us find the block containing a given offset.
+	block_sizes: Vec<u64>,
+	/// When the archive was created, used as the timestamp for the root and archive directories.
+	created: i64,
+	/// The codec of the archive the file belongs to
+	compression: Compression,
+}
+
+impl Node {
+	fn new(parent: u64, file: Option<File>, created: i64) -> Node {
+		Node {
+			parent,
+			file,
+			children: BTreeMap::new(),
+			unloaded_archive: None,
+			block_sizes: Vec::new(),
+			created,
+			compression: Compression::None,
+		}
+	}
+}
+
+
+/// A read-only filesystem with a directory for every archive in the backend.
+/// Archives are fetched and decrypted the first time their directory is accessed, and blocks are fetched
+/// and decrypted when they are read.
+struct ArchiveFilesystem {
+	keystore: KeyStore,
+	backend: Box<dyn Backend>,
+	/// Indexed by inode - 1
+	nodes: Vec<Node>,
+	block_cache: LruCache<BlockId, Rc<Vec<u8>>>,
+	uid: u32,
+	gid: u32,
+}
+
+impl ArchiveFilesystem {
+	fn new(keystore: KeyStore, mut backend: Box<dyn Backend>) -> Result<ArchiveFilesystem> {
+		let mut root = Node::new(ROOT_INO, None, 0);
+		let mut nodes = Vec::new();
+
+		for (archive_id, encrypted_archive_name) in backend.list_archives()? {
+			let archive_name = match keystore.decrypt_archive_name(&archive_id, &encrypted_archive_name) {
+				Ok(name) => name,
+				Err(err) => {
+					warn!("Could not decrypt one of the archive names belonging to ArchiveID: {}, because: {}", archive_id.to_string(), err);
+					continue;
+				}
+			};
+
+			let ino = nodes.len() as u64 + 2;
+			let mut node = Node::new(ROOT_INO, None, 0);
+			node.unloaded_archive = Some(archive_name.clone());
+			root.children.insert(OsString::from(archive_name), ino);
+			nodes.push(node);
+		}
+
+		nodes.insert(0, root);
+
+		Ok(ArchiveFilesystem {
+			keystore,
+			backend,
+			nodes,
+			block_cache: LruCache::new(BLOCK_CACHE_SIZE),
+			uid: unsafe { libc::geteuid() },
+			gid: unsafe { libc::getegid() },
+		})
+	}
+
+	fn node(&self, ino: u64) -> Option<&Node> {
+		self.nodes.get((ino as usize).wrapping_sub(1))
+	}
+
+	/// Make sure the node's children are available, fetching its archive if it hasn't been yet.
+	fn load(&mut self, ino: u64) -> Result<()> {
+		let archive_name = match self.node(ino).and_then(|node| node.unloaded_archive.clone()) {
+			Some(name) => name,
+			None => return Ok(()),
+		};
+
+		info!("Fetching backup: {}", archive_name);
+		let archive = Archive::fetch(&archive_name, &self.keystore, &mut *self.backend)?;
+		let created = archive.created.unwrap_or(0);
+		let compression = archive.compression();
+		// Archive paths -> inodes.  Directories are always listed before their contents.
+		let mut inodes: HashMap<String, u64> = HashMap::new();
+
+		for file in archive.files {
+			let path = Path::new(&file.path);
+			let name = match path.file_name() {
+				Some(name) => name.to_os_string(),
+				None => continue,
+			};
+			let parent = match path.parent().map(|parent| parent.to_string_lossy().to_string()) {
+				Some(ref parent) if parent.is_empty() => ino,
+				Some(ref parent) => match inodes.get(parent) {
+					Some(parent) => *parent,
+					None => {
+						warn!("'{}' in '{}' has no parent directory in the backup; skipping it", file.path, archive_name);
+						continue;
+					}
+				},
+				None => continue,
+			};
+			let child = self.nodes.len() as u64 + 1;
+
+			inodes.insert(file.path.clone(), child);
+			self.nodes[parent as usize - 1].children.insert(name, child);
+			let mut node = Node::new(parent, Some(file), created);
+			node.compression = compression;
+			self.nodes.push(node);
+		}
+
+		let node = &mut self.nodes[ino as usize - 1];
+		node.unloaded_archive = None;
+		node.created = created;
+
+		Ok(())
+	}
+
+	fn attr(&self, ino: u64) -> Option<FileAttr> {
+		let node = self.node(ino)?;
+
+		let attr = match node.file {
+			Some(ref file) => {
+				let kind = file_type(file);
+				let size = match file.symlink {
+					Some(ref symlink) => symlink.len() as u64,
+					None => file.size,
+				};
+				let mtime = Timespec::new(file.mtime, file.mtime_nsec as i32);
+
+				FileAttr {
+					ino,
+					size,
+					blocks: size.div_ceil(512),
+					atime: mtime,
+					mtime,
+					ctime: mtime,
+					crtime: mtime,
+					kind,
+					perm: (file.mode & 0o7777) as u16,
+					nlink: 1,
+					uid: file.uid,
+					gid: file.gid,
+					rdev: file.special.map_or(0, |special| special.rdev() as u32),
+					flags: 0,
+				}
+			},
+			None => {
+				let time = Timespec::new(node.created, 0);
+
+				FileAttr {
+					ino,
+					size: 0,
+					blocks: 0,
+					atime: time,
+					mtime: time,
+					ctime: time,
+					crtime: time,
+					kind: FileType::Directory,
+					perm: 0o555,
+					nlink: 2,
+					uid: self.uid,
+					gid: self.gid,
+					rdev: 0,
+					flags: 0,
+				}
+			},
+		};
+
+		Some(attr)
+	}
+
+	/// Read up to size bytes at offset from the file, filling in any holes with zeros.
+	fn read_file(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>> {
+		let (file_size, extents) = {
+			let file = self.nodes[ino as usize - 1].file.as_ref().expect("internal error");
+			(file.size, sparse::data_extents(&file.holes, file.size))
+		};
+		let end = cmp::min(offset.saturating_add(size), file_size);
+		let mut result = Vec::new();
+		// Where the current extent starts in the file's data (its blocks)
+		let mut data_offset = 0;
+
+		for (extent_offset, extent_length) in extents {
+			let from = cmp::max(offset, extent_offset);
+			let to = cmp::min(end, extent_offset + extent_length);
+
+			if from < to {
+				result.resize((from - offset) as usize, 0);
+				result.extend(self.read_data(ino, data_offset + from - extent_offset, to - from)?);
+			}
+
+			data_offset += extent_length;
+		}
+
+		if end > offset {
+			result.resize((end - offset) as usize, 0);
+		}
+
+		Ok(result)
+	}
+
+	/// Read up to size bytes at offset from the file's data, which is the file minus any holes.
+	fn read_data(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>> {
+		let node = &mut self.nodes[ino as usize - 1];
+		let file = node.file.as_ref().expect("internal error");
+		let end = cmp::min(offset.saturating_add(size), file.size - sparse::hole_size(&file.holes));
+		let mut result = Vec::new();
+		let mut block_start = 0;
+
+		for (index, block_id) in file.blocks.iter().enumerate() {
+			if block_start >= end {
+				break;
+			}
+
+			// Blocks before the offset only need to be fetched if we don't know their size yet
+			let block = if index >= node.block_sizes.len() || block_start + node.block_sizes[index] > offset {
+				let block = fetch_block(block_id, node.compression, &self.keystore, &mut *self.backend, &mut self.block_cache)?;
+
+				if index >= node.block_sizes.len() {
+					node.block_sizes.push(block.len() as u64);
+				}
+
+				Some(block)
+			} else {
+				None
+			};
+			let block_size = node.block_sizes[index];
+
+			if let Some(block) = block {
+				if block_start + block_size > offset {
+					let from = offset.saturating_sub(block_start) as usize;
+					let to = cmp::min(block_size, end - block_start) as usize;
+					result.extend_from_slice(&block[from..to]);
+				}
+			}
+
+			block_start += block_size;
+		}
+
+		Ok(result)
+	}
+}
+
+impl Filesystem for ArchiveFilesystem {
+	fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+		if let Err(err) = self.load(parent) {
+			error!("There was a problem fetching the backup: {}", err);
+			return reply.error(EIO);
+		}
+
+		let child = self.node(parent).and_then(|node| node.children.get(name).cloned());
+
+		match child.and_then(|child| self.attr(child)) {
+			Some(attr) => reply.entry(&TTL, &attr, 0),
+			None => reply.error(ENOENT),
+		}
+	}
+
+	fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+		match self.attr(ino) {
+			Some(attr) => reply.attr(&TTL, &attr),
+			None => reply.error(ENOENT),
+		}
+	}
+
+	fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+		match self.node(ino).and_then(|node| node.file.as_ref()).and_then(|file| file.symlink.as_ref()) {
+			Some(symlink) => reply.data(symlink.as_bytes()),
+			None => reply.error(EINVAL),
+		}
+	}
+
+	fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, reply: ReplyData) {
+		match self.node(ino).map(|node| node.file.as_ref().map(|file| file.is_dir || file.symlink.is_some())) {
+			None => return reply.error(ENOENT),
+			Some(None) | Some(Some(true)) => return reply.error(EISDIR),
+			Some(Some(false)) => (),
+		}
+
+		if offset < 0 {
+			return reply.error(EINVAL);
+		}
+
+		match self.read_file(ino, offset as u64, size as u64) {
+			Ok(data) => reply.data(&data),
+			Err(err) => {
+				error!("There was a problem reading a file: {}", err);
+				reply.error(EIO);
+			}
+		}
+	}
+
+	fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+		if let Err(err) = self.load(ino) {
+			error!("There was a problem fetching the backup: {}", err);
+			return reply.error(EIO);
+		}
+
+		let node = match self.node(ino) {
+			Some(node) => node,
+			None => return reply.error(ENOENT),
+		};
+
+		let mut entries = vec![(ino, FileType::Directory, OsString::from(".")), (node.parent, FileType::Directory, OsString::from(".."))];
+
+		for (name, child) in &node.children {
+			let kind = self.node(*child).and_then(|child| child.file.as_ref()).map_or(FileType::Directory, file_type);
+			entries.push((*child, kind, name.clone()));
+		}
+
+		// The offset given to add is the offset of the *next* entry
+		for (index, (child, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+			if reply.add(child, index as i64 + 1, kind, name) {
+				break;
+			}
+		}
+
+		reply.ok();
+	}
+}
+
+
+fn file_type(file: &File) -> FileType {
+	if let Some(special) = file.special {
+		match special {
+			SpecialFile::Fifo => FileType::NamedPipe,
+			SpecialFile::BlockDevice { .. } => FileType::BlockDevice,
+			SpecialFile::CharDevice { .. } => FileType::CharDevice,
+		}
+	} else if file.symlink.is_some() {
+		FileType::Symlink
+	} else if file.is_dir {
+		FileType::Directory
+	} else {
+		FileType::RegularFile
+	}
+}
+
+
+fn fetch_block(block_id: &BlockId, compression: Compression, keystore: &KeyStore, backend: &mut dyn Backend, cache: &mut LruCache<BlockId, Rc<Vec<u8>>>) -> Result<Rc<Vec<u8>>> {
+	if let Some(block) = cache.get(block_id) {
+		return Ok(block.clone());
+	}
+
+	let encrypted_block = backend.fetch_block(block_id)?;
+	let block = Rc::new(keystore.decrypt_block(block_id, &encrypted_block, compression)?);
+
+	cache.put(*block_id, block.clone());
+
+	Ok(block)
+}
@@ -0,0 +1,241 @@
+use crate::keystore::{KeyStore, ArchiveId};
+use crate::backend;
+use crate::archive::Archive;
+use clap::ArgMatches;
+use log::{error, info, warn};
+use std::cmp::Reverse;
+
+
+pub fn execute(args: &ArgMatches) {
+	let args_keyfile = args.value_of("keyfile").expect("internal error");
+	let args_backend = args.value_of("backend").expect("internal error");
+	let dry_run = args.is_present("dry-run");
+
+	let policy = match parse_policy(args) {
+		Some(policy) => policy,
+		None => return,
+	};
+
+	if policy.is_empty() {
+		error!("At least one --keep-* option must be given; refusing to delete every backup");
+		return;
+	}
+
+	let keystore = match KeyStore::load_from_path(args_keyfile) {
+		Ok(keystore) => keystore,
+		Err(err) => {
+			error!("Unable to load keyfile: {}", err);
+			return;
+		}
+	};
+
+	let mut backend = match backend::backend_from_backend_path(args_backend) {
+		Ok(backend) => backend,
+		Err(err) => {
+			error!("Unable to load backend: {}", err);
+			return;
+		}
+	};
+
+	let encrypted_archive_names = match backend.list_archives() {
+		Ok(names) => names,
+		Err(err) => {
+			error!("There was a problem listing the archives: {}", err);
+			return;
+		}
+	};
+
+	// (id, name, created) for every archive that has a creation time
+	let mut archives: Vec<(ArchiveId, String, i64)> = Vec::new();
+
+	for (archive_id, encrypted_archive_name) in &encrypted_archive_names {
+		let archive_name = match keystore.decrypt_archive_name(archive_id, encrypted_archive_name) {
+			Ok(name) => name,
+			Err(err) => {
+				error!("Could not decrypt one of the archive names belonging to ArchiveID: {}, because: {}", archive_id.to_string(), err);
+				return;
+			}
+		};
+
+		let archive = match backend.fetch_archive(archive_id).and_then(|encrypted_archive| Archive::decrypt(archive_id, &encrypted_archive, &keystore)) {
+			Ok(archive) => archive,
+			Err(err) => {
+				error!("There was a problem reading the backup '{}': {}", archive_name, err);
+				return;
+			}
+		};
+
+		match archive.created {
+			Some(created) => archives.push((*archive_id, archive_name, created)),
+			None => warn!("'{}' does not record when it was created, so it will be kept.", archive_name),
+		}
+	}
+
+	// Newest first
+	archives.sort_by_key(|archive| Reverse(archive.2));
+
+	let created: Vec<i64> = archives.iter().map(|archive| archive.2).collect();
+	let reasons = policy.apply(&created);
+	let mut to_delete = Vec::new();
+
+	for ((archive_id, archive_name, created), reasons) in archives.iter().zip(reasons) {
+		let timestamp = time::strftime("%Y-%m-%d %H:%M:%S", &time::at(time::Timespec::new(*created, 0))).expect("internal error");
+
+		if reasons.is_empty() {
+			info!("Prune: {} ({})", archive_name, timestamp);
+			to_delete.push((archive_id, archive_name));
+		} else {
+			info!("Keep:  {} ({}) [{}]", archive_name, timestamp, reasons.join(", "));
+		}
+	}
+
+	if dry_run {
+		info!("Dry run; {} backups would be deleted", to_delete.len());
+		return;
+	}
+
+	for (archive_id, archive_name) in &to_delete {
+		match backend.delete_archive(archive_id) {
+			Ok(_) => info!("Deleted: {}", archive_name),
+			Err(err) => {
+				error!("There was a problem deleting the backup '{}': {}", archive_name, err);
+				return;
+			}
+		}
+	}
+
+	info!("Deleted {} backups.  Run gc to remove any blocks that are no longer referenced.", to_delete.len());
+}
+
+
+fn parse_policy(args: &ArgMatches) -> Option<RetentionPolicy> {
+	let parse_count = |name: &str| match args.value_of(name) {
+		Some(value) => match value.parse() {
+			Ok(count) => Some(count),
+			Err(_) => {
+				error!("--{} must be a number", name);
+				None
+			}
+		},
+		None => Some(0),
+	};
+
+	Some(RetentionPolicy {
+		keep_last: parse_count("keep-last")?,
+		keep_daily: parse_count("keep-daily")?,
+		keep_weekly: parse_count("keep-weekly")?,
+		keep_monthly: parse_count("keep-monthly")?,
+	})
+}
+
+
+#[derive(Default)]
+struct RetentionPolicy {
+	/// Keep the N newest archives
+	keep_last: usize,
+	/// Keep the newest archive of each of the N most recent days that have archives
+	keep_daily: usize,
+	/// Same as keep_daily, but for ISO weeks
+	keep_weekly: usize,
+	/// Same as keep_daily, but for months
+	keep_monthly: usize,
+}
+
+impl RetentionPolicy {
+	fn is_empty(&self) -> bool {
+		self.keep_last == 0 && self.keep_daily == 0 && self.keep_weekly == 0 && self.keep_monthly == 0
+	}
+
+	/// Given the creation times of archives, sorted newest first, returns the list of rules
+	/// which keep each archive.  Archives with no rules keeping them should be pruned.
+	/// Periods are bucketed using local time.
+	fn apply(&self, created: &[i64]) -> Vec<Vec<&'static str>> {
+		let mut reasons = vec![Vec::new(); created.len()];
+
+		for reason in reasons.iter_mut().take(self.keep_last) {
+			reason.push("last");
+		}
+
+		for &(rule, count, format) in &[
+			("daily", self.keep_daily, "%Y-%m-%d"),
+			("weekly", self.keep_weekly, "%G-%V"),
+			("monthly", self.keep_monthly, "%Y-%m"),
+		] {
+			let mut last_period = None;
+			let mut kept = 0;
+
+			for (timestamp, reason) in created.iter().zip(reasons.iter_mut()) {
+				if kept >= count {
+					break;
+				}
+
+				let period = time::strftime(format, &time::at(time::Timespec::new(*timestamp, 0))).expect("internal error");
+
+				if last_period.as_ref() != Some(&period) {
+					reason.push(rule);
+					kept += 1;
+					last_period = Some(period);
+				}
+			}
+		}
+
+		reasons
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::RetentionPolicy;
+
+	const HOUR: i64 = 60 * 60;
+	const DAY: i64 = 24 * HOUR;
+
+	// Noon on Monday 2019-07-01 UTC.  Far enough from midnight that any local timezone lands on the same day.
+	const BASE: i64 = 1561982400;
+
+	fn kept(policy: &RetentionPolicy, created: &[i64]) -> Vec<bool> {
+		policy.apply(created).iter().map(|reasons| !reasons.is_empty()).collect()
+	}
+
+	#[test]
+	fn test_keep_last() {
+		let policy = RetentionPolicy { keep_last: 2, ..Default::default() };
+		let created = [BASE, BASE - HOUR, BASE - 2 * HOUR];
+
+		assert_eq!(kept(&policy, &created), vec![true, true, false]);
+	}
+
+	#[test]
+	fn test_keep_daily() {
+		// Two archives a day for four days; only the newest of each of the three newest days should be kept
+		let created: Vec<i64> = (0..8).map(|i| BASE - (i / 2) * DAY - (i % 2) * HOUR).collect();
+		let policy = RetentionPolicy { keep_daily: 3, ..Default::default() };
+
+		assert_eq!(kept(&policy, &created), vec![true, false, true, false, true, false, false, false]);
+	}
+
+	#[test]
+	fn test_keep_weekly_and_monthly() {
+		// One archive a day for 60 days
+		let created: Vec<i64> = (0..60).map(|i| BASE - i * DAY).collect();
+		let policy = RetentionPolicy { keep_weekly: 2, keep_monthly: 2, ..Default::default() };
+		let reasons = policy.apply(&created);
+
+		assert_eq!(reasons.iter().filter(|r| r.contains(&"weekly")).count(), 2);
+		assert_eq!(reasons.iter().filter(|r| r.contains(&"monthly")).count(), 2);
+		// The newest archive is kept by every rule that applies
+		assert_eq!(reasons[0], vec!["weekly", "monthly"]);
+		// BASE is Monday the 1st, so the day before it is the newest archive of both the previous week and the previous month
+		assert_eq!(reasons[1], vec!["weekly", "monthly"]);
+		assert!(reasons.iter().skip(2).all(|r| r.is_empty()));
+	}
+
+	#[test]
+	fn test_empty_policy_keeps_nothing() {
+		let policy = RetentionPolicy::default();
+
+		assert!(policy.is_empty());
+		assert_eq!(kept(&policy, &[BASE, BASE - DAY]), vec![false, false]);
+	}
+}
@@ -2,13 +2,19 @@ use crate::keystore::{KeyStore, BlockId};
 use std::fs;
 use std::io::{self, BufWriter, Write, Read};
 use std::path::{Path, PathBuf};
-use std::os::unix::fs::PermissionsExt;
-use std::collections::HashMap;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::collections::{HashMap, HashSet};
 use crate::backend::{self, Backend};
-use crate::archive::{Archive, File};
+use crate::archive::{Archive, File, SpecialFile};
 use clap::ArgMatches;
 use crate::error::*;
-use log::{error, info};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{error, info, warn};
+use crate::users;
+use crate::xattrs;
+use crate::sparse::{DataReader, DataWriter};
+use crate::chunker::Chunker;
+use crate::compression::Compression;
 
 
 struct DownloadCache {
@@ -18,6 +24,18 @@ struct DownloadCache {
 }
 
 
+/// Everything needed to fetch a file's blocks during a restore.
+/// Blocks used more than once are kept in cache_dir until their last use.
+struct Downloader<'a> {
+	keystore: &'a KeyStore,
+	/// The archive's codec
+	compression: Compression,
+	backend: &'a mut dyn Backend,
+	cache_dir: &'a Path,
+	download_cache: &'a mut HashMap<BlockId, DownloadCache>,
+}
+
+
 pub fn execute(args: &ArgMatches) {
 	let debug_decrypt = args.is_present("debug-decrypt");
 
@@ -59,6 +77,52 @@ pub fn execute(args: &ArgMatches) {
 	let mut config = Config::default();
 
 	config.dereference_hardlinks = args.is_present("hard-dereference");
+	config.existing_dirs_ok = args.is_present("existing-dirs-ok");
+	config.resume = args.is_present("resume");
+	config.check_content = args.is_present("check-content");
+	config.same_owner = !args.is_present("no-same-owner");
+	config.numeric_owner = args.is_present("numeric-owner");
+	config.restore_atime = args.is_present("restore-atime");
+	config.xattr_namespaces = match xattrs::parse_namespaces(args.value_of("xattr-namespaces").unwrap_or("")) {
+		Some(namespaces) => namespaces,
+		None => {
+			error!("Invalid --xattr-namespaces.  Expected a comma separated list of: {}", xattrs::NAMESPACES.join(", "));
+			return;
+		}
+	};
+	config.on_conflict = match args.value_of("on-conflict").unwrap_or("error") {
+		"error" => ConflictPolicy::Error,
+		"skip" => ConflictPolicy::Skip,
+		"overwrite" => ConflictPolicy::Overwrite,
+		"if-newer" => ConflictPolicy::IfNewer,
+		"rename" => ConflictPolicy::Rename,
+		_ => panic!("internal error"),
+	};
+
+	if config.resume {
+		// Directories from the previous attempt are expected, and anything that doesn't match
+		// the backup (like a partially written file) needs to be replaced.
+		config.existing_dirs_ok = true;
+
+		if args.occurrences_of("on-conflict") == 0 {
+			config.on_conflict = ConflictPolicy::Overwrite;
+		}
+	}
+
+	let includes = match build_globset(args.values_of("include").unwrap_or_default()) {
+		Ok(globset) => globset,
+		Err(err) => {
+			error!("Invalid --include pattern: {}", err);
+			return;
+		}
+	};
+	let excludes = match build_globset(args.values_of("exclude").unwrap_or_default()) {
+		Ok(globset) => globset,
+		Err(err) => {
+			error!("Invalid --exclude pattern: {}", err);
+			return;
+		}
+	};
 
 	let (archive_id, _) = keystore.encrypt_archive_name(&backup_name);
 	let encrypted_archive = match backend.fetch_archive(&archive_id) {
@@ -89,11 +153,6 @@ pub fn execute(args: &ArgMatches) {
 		}
 	};
 
-	if archive.version != 0x00000001 {
-		error!("Unsupported archive version");
-		return;
-	}
-
 	let download_cache_dir = match tempfile::Builder::new().prefix("preserve-").tempdir() {
 		Ok(dir) => dir,
 		Err(err) => {
@@ -103,7 +162,33 @@ pub fn execute(args: &ArgMatches) {
 	};
 	let mut download_cache = HashMap::new();
 
-	match build_block_refcounts(&archive.files, &mut download_cache) {
+	let mut owners = Owners::new(&config, &archive);
+	let chunker = Chunker::new(archive.chunking(), &keystore);
+	let compression = archive.compression();
+	let files = select_files(archive.files, &includes, &excludes);
+
+	if files.is_empty() {
+		error!("Nothing in the backup matched the given --include/--exclude patterns");
+		return;
+	}
+
+	let restored = if config.resume {
+		match find_restored_files(&config, &files, &target_directory, &chunker, compression, &keystore) {
+			Ok(restored) => restored,
+			Err(err) => {
+				error!("There was a problem checking for already restored files: {}", err);
+				return;
+			},
+		}
+	} else {
+		HashSet::new()
+	};
+
+	if config.resume {
+		info!("{} files are already restored and will be skipped", restored.len());
+	}
+
+	match build_block_refcounts(files.iter().filter(|file| !restored.contains(&file.path)), &mut download_cache) {
 		Ok(x) => x,
 		Err(err) => {
 			error!("There was a problem reading the backup: {}", err);
@@ -111,7 +196,15 @@ pub fn execute(args: &ArgMatches) {
 		},
 	}
 
-	match extract_files(&config, &archive.files, target_directory, &keystore, download_cache_dir.path(), &mut download_cache, &mut *backend) {
+	let mut downloader = Downloader {
+		keystore: &keystore,
+		compression,
+		backend: &mut *backend,
+		cache_dir: download_cache_dir.path(),
+		download_cache: &mut download_cache,
+	};
+
+	match extract_files(&config, &files, &restored, &mut owners, target_directory, &mut downloader) {
 		Ok(x) => x,
 		Err(err) => {
 			error!("There was a problem extracting the backup: {}", err);
@@ -128,10 +221,93 @@ struct Config {
 	/// If true, hardlinks will be removed by cloning the file at all places it is referenced.
 	/// If false, hardlinks are preserved.
 	pub dereference_hardlinks: bool,
+	/// What to do when something already exists where a file is being restored.
+	pub on_conflict: ConflictPolicy,
+	/// If true, directories in the backup are restored into existing directories (merging their contents) regardless of on_conflict.
+	pub existing_dirs_ok: bool,
+	/// If true, files that already exist with the right size and mtime are assumed to have been restored
+	/// by an earlier, interrupted, restore and are skipped.
+	pub resume: bool,
+	/// If true, resume also checks the contents of existing files against the backup.
+	pub check_content: bool,
+	/// If true, restored files are chowned to the owner and group recorded in the backup.
+	pub same_owner: bool,
+	/// If true, use the uids and gids recorded in the backup as-is, rather than looking up the local users and groups with the same names.
+	pub numeric_owner: bool,
+	/// Only extended attributes in these namespaces are restored.
+	pub xattr_namespaces: Vec<String>,
+	/// If true, restore access times as well.  Otherwise a file's atime is set to its mtime.
+	pub restore_atime: bool,
 }
 
 
-fn build_block_refcounts(files: &[File], download_cache: &mut HashMap<BlockId, DownloadCache>) -> Result<()> {
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+enum ConflictPolicy {
+	/// Stop the restore
+	#[default]
+	Error,
+	/// Leave the existing path alone
+	Skip,
+	/// Replace the existing path.  Existing directories are restored into, never deleted.
+	Overwrite,
+	/// Like Overwrite, but only if the backup's copy has a newer mtime
+	IfNewer,
+	/// Move the existing path out of the way (to PATH.~N~)
+	Rename,
+}
+
+/// How to restore a File, once any conflict with what's already on disk has been dealt with.
+#[derive(PartialEq, Debug)]
+enum Resolution {
+	/// Nothing is in the way
+	Create,
+	/// The directory already exists; restore into it and update its permissions and mtime
+	Merge,
+	/// Leave whatever is there alone
+	Skip,
+}
+
+
+fn build_globset<'a, I: Iterator<Item = &'a str>>(patterns: I) -> ::std::result::Result<GlobSet, globset::Error> {
+	let mut builder = GlobSetBuilder::new();
+
+	for pattern in patterns {
+		builder.add(Glob::new(pattern)?);
+	}
+
+	builder.build()
+}
+
+
+/// Picks out the files to extract.  A file is selected if it, or one of the directories containing it, matches
+/// any of the includes (or there are no includes) and none of the excludes.  The directories containing selected
+/// files are always selected as well, so they can be created.  The archive's order is preserved.
+fn select_files(files: Vec<File>, includes: &GlobSet, excludes: &GlobSet) -> Vec<File> {
+	if includes.is_empty() && excludes.is_empty() {
+		return files;
+	}
+
+	let mut selected: HashSet<&Path> = HashSet::new();
+
+	for file in &files {
+		let path = Path::new(&file.path);
+		let mut ancestors = path.ancestors().filter(|ancestor| ancestor.as_os_str() != "");
+
+		let included = includes.is_empty() || ancestors.clone().any(|ancestor| includes.is_match(ancestor));
+		let excluded = ancestors.any(|ancestor| excludes.is_match(ancestor));
+
+		if included && !excluded {
+			selected.extend(path.ancestors().filter(|ancestor| ancestor.as_os_str() != ""));
+		}
+	}
+
+	let selected: HashSet<String> = selected.into_iter().map(|path| path.to_string_lossy().to_string()).collect();
+
+	files.into_iter().filter(|file| selected.contains(&file.path)).collect()
+}
+
+
+fn build_block_refcounts<'a, I: Iterator<Item = &'a File>>(files: I, download_cache: &mut HashMap<BlockId, DownloadCache>) -> Result<()> {
 	for file in files {
 		build_block_refcounts_helper(file, download_cache)?;
 	}
@@ -154,27 +330,81 @@ fn build_block_refcounts_helper(file: &File, download_cache: &mut HashMap<BlockI
 }
 
 
-fn extract_files<P: AsRef<Path>>(config: &Config, files: &[File], base_path: P, keystore: &KeyStore, cache_dir: &Path, download_cache: &mut HashMap<BlockId, DownloadCache>, backend: &mut dyn Backend) -> Result<()> {
+fn extract_files<P: AsRef<Path>>(config: &Config, files: &[File], restored: &HashSet<String>, owners: &mut Owners, base_path: P, downloader: &mut Downloader) -> Result<()> {
 	let mut hardlink_map: HashMap<u64, PathBuf> = HashMap::new();
 	// List of all directories and the mtimes they need set.
 	// We set these after extracting all files, since extracting the files changes the mtime of
 	// directories.
 	let mut directory_times = Vec::new();
+	// Directories that were skipped because something other than a directory is in the way.
+	// Their contents have to be skipped too.
+	let mut skipped_directories: HashSet<PathBuf> = HashSet::new();
 
 	for file in files {
 		let filepath = base_path.as_ref().join(&file.path);
 
+		if filepath.parent().is_some_and(|parent| skipped_directories.contains(parent)) {
+			if file.is_dir && file.symlink.is_none() {
+				skipped_directories.insert(filepath);
+			}
+			continue;
+		}
+
+		if restored.contains(&file.path) {
+			// Later links to this file can be hardlinked to it
+			if !config.dereference_hardlinks {
+				if let Some(hardlink_id) = file.hardlink_id {
+					hardlink_map.entry(hardlink_id).or_insert_with(|| filepath.clone());
+				}
+			}
+			continue;
+		}
+
+		match resolve_conflict(config, &filepath, file)? {
+			Resolution::Create => (),
+			Resolution::Merge => {
+				info!("Restoring into existing directory: {}", filepath.display());
+				owners.chown(&filepath, file)?;
+				fs::set_permissions(&filepath, fs::Permissions::from_mode(file.mode))?;
+				restore_xattrs(config, &filepath, file);
+				directory_times.push((filepath.clone(), file));
+				continue;
+			},
+			Resolution::Skip => {
+				info!("Skipping existing path: {}", filepath.display());
+				let is_existing_dir = fs::symlink_metadata(&filepath).map(|metadata| metadata.is_dir()).unwrap_or(false);
+
+				if file.is_dir && file.symlink.is_none() && !is_existing_dir {
+					skipped_directories.insert(filepath);
+				}
+				continue;
+			},
+		}
+
 		if let Some(ref symlink_path) = file.symlink {
 			use std::os::unix;
 			info!("Creating symlink: {} {}", symlink_path, filepath.display());
 			unix::fs::symlink(symlink_path, &filepath)?;
+			owners.chown(&filepath, file)?;
+			restore_xattrs(config, &filepath, file);
 		} else if file.is_dir {
 			info!("Creating directory: {}", filepath.display());
 			// Create and then set permissions.  This is done in two steps because
 			// mkdir is affected by the current process's umask, whereas chmod (set_permissions) is not.
+			// chown comes before chmod, since chown clears the setuid and setgid bits.
 			fs::create_dir(&filepath)?;
+			owners.chown(&filepath, file)?;
 			fs::set_permissions(&filepath, fs::Permissions::from_mode(file.mode))?;
-			directory_times.push((filepath.clone(), file.mtime, file.mtime_nsec));
+			restore_xattrs(config, &filepath, file);
+			directory_times.push((filepath.clone(), file));
+		} else if let Some(special) = file.special {
+			if !make_special_file(&filepath, special, file.mode)? {
+				continue;
+			}
+
+			owners.chown(&filepath, file)?;
+			fs::set_permissions(&filepath, fs::Permissions::from_mode(file.mode))?;
+			restore_xattrs(config, &filepath, file);
 		} else {
 			let hardlinked = if let Some(hardlink_id) = file.hardlink_id {
 				if config.dereference_hardlinks {
@@ -196,8 +426,11 @@ fn extract_files<P: AsRef<Path>>(config: &Config, files: &[File], base_path: P,
 			if !hardlinked {
 				info!("Writing file: {}", filepath.display());
 				// We set permissions after creating the file because `open` uses umask.
-				extract_file(&filepath, file, keystore, cache_dir, download_cache, backend)?;
+				extract_file(&filepath, file, downloader)?;
+				owners.chown(&filepath, file)?;
 				fs::set_permissions(&filepath, fs::Permissions::from_mode(file.mode))?;
+				// Last, since chown drops file capabilities and chmod rewrites the ACL mask
+				restore_xattrs(config, &filepath, file);
 
 				if !config.dereference_hardlinks {
 					if let Some(hardlink_id) = file.hardlink_id {
@@ -207,35 +440,241 @@ fn extract_files<P: AsRef<Path>>(config: &Config, files: &[File], base_path: P,
 			}
 		}
 
-		set_file_time(&filepath, file.mtime, file.mtime_nsec)?;
+		set_file_time(&filepath, file, config.restore_atime)?;
 	}
 
 	// Set mtime for directories.
 	// We go in reverse, so we hit child directories before their parents
 	directory_times.reverse();
 
-	for (ref dirpath, ref mtime, ref mtime_nsec) in directory_times {
-		set_file_time(dirpath, *mtime, *mtime_nsec)?;
+	for (ref dirpath, file) in directory_times {
+		set_file_time(dirpath, file, config.restore_atime)?;
 	}
 
 	Ok(())
 }
 
 
-fn extract_file<P: AsRef<Path>>(path: P, f: &File, keystore: &KeyStore, cache_dir: &Path, download_cache: &mut HashMap<BlockId, DownloadCache>, backend: &mut dyn Backend) -> Result<()> {
+/// Maps the owners recorded in the archive to local users and groups, and applies them to restored files.
+struct Owners {
+	enabled: bool,
+	/// Archive uid -> local uid, for users that exist on this machine under the same name
+	uids: HashMap<u32, u32>,
+	/// Archive gid -> local gid
+	gids: HashMap<u32, u32>,
+}
+
+impl Owners {
+	fn new(config: &Config, archive: &Archive) -> Owners {
+		let mut owners = Owners {
+			enabled: config.same_owner,
+			uids: HashMap::new(),
+			gids: HashMap::new(),
+		};
+
+		// Owners without a name in the archive, or whose name doesn't exist here, are restored by number
+		if !config.numeric_owner {
+			owners.uids = archive.user_names.iter().filter_map(|(uid, name)| users::user_id(name).map(|local_uid| (*uid, local_uid))).collect();
+			owners.gids = archive.group_names.iter().filter_map(|(gid, name)| users::group_id(name).map(|local_gid| (*gid, local_gid))).collect();
+		}
+
+		owners
+	}
+
+	/// Set the owner and group of path (not following symlinks) to those of file.
+	/// If we aren't allowed to (i.e. we aren't root), warn once and stop trying.
+	fn chown(&mut self, path: &Path, file: &File) -> Result<()> {
+		use std::ffi::CString;
+		use std::os::unix::prelude::*;
+
+		if !self.enabled {
+			return Ok(());
+		}
+
+		let uid = self.uids.get(&file.uid).cloned().unwrap_or(file.uid);
+		let gid = self.gids.get(&file.gid).cloned().unwrap_or(file.gid);
+		let p = CString::new(path.as_os_str().as_bytes()).expect("internal error");
+
+		if unsafe { libc::lchown(p.as_ptr(), uid, gid) } == 0 {
+			return Ok(());
+		}
+
+		let err = io::Error::last_os_error();
+
+		if err.raw_os_error() == Some(libc::EPERM) && unsafe { libc::geteuid() } != 0 {
+			warn!("Not permitted to change the owner of restored files (not running as root); ownership will not be restored.  Use --no-same-owner to silence this warning.");
+			self.enabled = false;
+			return Ok(());
+		}
+
+		Err(err.into())
+	}
+}
+
+
+/// Create a FIFO or device node with mknod.  Only root can create device nodes, so when we aren't root they are
+/// skipped with a warning and false is returned.
+fn make_special_file(path: &Path, special: SpecialFile, mode: u32) -> Result<bool> {
+	use std::ffi::CString;
+	use std::os::unix::prelude::*;
+
+	let file_type = match special {
+		SpecialFile::Fifo => libc::S_IFIFO,
+		SpecialFile::BlockDevice { .. } => libc::S_IFBLK,
+		SpecialFile::CharDevice { .. } => libc::S_IFCHR,
+	};
+
+	if special != SpecialFile::Fifo && unsafe { libc::geteuid() } != 0 {
+		warn!("Skipping device '{}' because only root can create devices.", path.display());
+		return Ok(false);
+	}
+
+	info!("Creating {}: {}", if special == SpecialFile::Fifo { "FIFO" } else { "device" }, path.display());
+	let p = CString::new(path.as_os_str().as_bytes()).expect("internal error");
+
+	if unsafe { libc::mknod(p.as_ptr(), file_type | (mode & 0o7777), special.rdev() as libc::dev_t) } == 0 {
+		Ok(true)
+	} else {
+		Err(io::Error::last_os_error().into())
+	}
+}
+
+
+/// Set the extended attributes recorded for file on path.  Failures (e.g. the filesystem doesn't support them, or
+/// we need to be root to set security.* attributes) are only warnings, since the file itself was restored fine.
+fn restore_xattrs(config: &Config, path: &Path, file: &File) {
+	for (name, value) in &file.xattrs {
+		if !config.xattr_namespaces.iter().any(|namespace| name.split('.').next() == Some(namespace)) {
+			continue;
+		}
+
+		if let Err(err) = xattrs::write(path, name, value) {
+			warn!("Unable to restore extended attribute '{}' on '{}': {}", name, path.display(), err);
+		}
+	}
+}
+
+
+/// Finds the files (by path in the archive) which already exist under base_path as they are in the backup:
+/// regular files with the same size and mtime (and BlockIds, if config.check_content), and symlinks with the same target.
+fn find_restored_files(config: &Config, files: &[File], base_path: &Path, chunker: &Chunker, compression: Compression, keystore: &KeyStore) -> Result<HashSet<String>> {
+	let mut restored = HashSet::new();
+
+	for file in files {
+		let path = base_path.join(&file.path);
+		let metadata = match fs::symlink_metadata(&path) {
+			Ok(metadata) => metadata,
+			Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+			Err(err) => return Err(err.into()),
+		};
+
+		let is_restored = if let Some(ref symlink_path) = file.symlink {
+			metadata.file_type().is_symlink() && fs::read_link(&path)? == Path::new(symlink_path)
+		} else if file.is_dir {
+			// Directories are always restored again (their contents may not be)
+			false
+		} else {
+			metadata.is_file() && metadata.len() == file.size && metadata.mtime() == file.mtime && metadata.mtime_nsec() == file.mtime_nsec &&
+				(!config.check_content || file_block_ids(&path, file, chunker, compression, keystore)? == file.blocks)
+		};
+
+		if is_restored {
+			restored.insert(file.path.clone());
+		}
+	}
+
+	Ok(restored)
+}
+
+
+/// Split and compress the file into blocks the same way create did and return their BlockIds.
+fn file_block_ids(path: &Path, file: &File, chunker: &Chunker, compression: Compression, keystore: &KeyStore) -> Result<Vec<BlockId>> {
+	let reader = fs::File::open(path)?;
+	// The regions that are holes in the backup weren't part of its blocks
+	let mut reader = chunker.chunks(DataReader::new(&reader, &file.holes, file.size));
+	let mut buffer = Vec::new();
+	let mut blocks = Vec::new();
+
+	loop {
+		reader.next_chunk(&mut buffer)?;
+
+		if buffer.is_empty() {
+			break;
+		}
+
+		blocks.push(keystore.encrypt_block(&buffer, compression).0);
+	}
+
+	Ok(blocks)
+}
+
+
+/// Decides what to do with `file` given whatever already exists at `path`, according to the conflict policy.
+/// For the Overwrite, IfNewer and Rename policies this may remove or rename the existing path.
+fn resolve_conflict(config: &Config, path: &Path, file: &File) -> Result<Resolution> {
+	let metadata = match fs::symlink_metadata(path) {
+		Ok(metadata) => metadata,
+		Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Resolution::Create),
+		Err(err) => return Err(err.into()),
+	};
+	let restoring_dir = file.is_dir && file.symlink.is_none();
+
+	if restoring_dir && metadata.is_dir() && config.existing_dirs_ok {
+		return Ok(Resolution::Merge);
+	}
+
+	match config.on_conflict {
+		ConflictPolicy::Error => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("'{}' already exists", path.display())).into()),
+		ConflictPolicy::Skip => Ok(Resolution::Skip),
+		ConflictPolicy::IfNewer if (metadata.mtime(), metadata.mtime_nsec()) >= (file.mtime, file.mtime_nsec) => Ok(Resolution::Skip),
+		ConflictPolicy::Overwrite | ConflictPolicy::IfNewer => {
+			if metadata.is_dir() {
+				if restoring_dir {
+					return Ok(Resolution::Merge);
+				}
+
+				return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("'{}' is an existing directory; refusing to replace it", path.display())).into());
+			}
+
+			info!("Replacing: {}", path.display());
+			fs::remove_file(path)?;
+			Ok(Resolution::Create)
+		},
+		ConflictPolicy::Rename => {
+			let renamed = (1..).map(|n| {
+				let mut renamed = path.as_os_str().to_os_string();
+				renamed.push(format!(".~{}~", n));
+				PathBuf::from(renamed)
+			}).find(|renamed| fs::symlink_metadata(renamed).is_err()).expect("internal error");
+
+			info!("Renaming '{}' to '{}'", path.display(), renamed.display());
+			fs::rename(path, &renamed)?;
+			Ok(Resolution::Create)
+		},
+	}
+}
+
+
+fn extract_file<P: AsRef<Path>>(path: P, f: &File, downloader: &mut Downloader) -> Result<()> {
 	// Don't overwrite existing files
 	let file = fs::OpenOptions::new().write(true).create_new(true).open(path.as_ref())?;
-	let mut writer = BufWriter::new(&file);
-	let mut total_written = 0;
+	// Holes are seeked over, leaving holes in the restored file
+	let mut writer = DataWriter::seeking(BufWriter::new(&file), &f.holes);
 
 	for block_id in &f.blocks {
-		let plaintext = cache_fetch(block_id, keystore, cache_dir, download_cache, backend)?;
+		let plaintext = cache_fetch(block_id, downloader)?;
 
 		writer.write_all(&plaintext)?;
-		total_written += plaintext.len();
 	}
 
-	if total_written as u64 != f.size {
+	let total_written = writer.finish()?;
+
+	// A hole at the end of the file only exists once the file is extended over it
+	if !f.holes.is_empty() {
+		file.set_len(total_written)?;
+	}
+
+	if total_written != f.size {
 		error!("The final extracted size of '{}' did not match what was expected: {} != {}", path.as_ref().display(), total_written, f.size);
 	}
 
@@ -243,9 +682,9 @@ fn extract_file<P: AsRef<Path>>(path: P, f: &File, keystore: &KeyStore, cache_di
 }
 
 
-fn cache_fetch(block_id: &BlockId, keystore: &KeyStore, cache_dir: &Path, download_cache: &mut HashMap<BlockId, DownloadCache>, backend: &mut dyn Backend) -> Result<Vec<u8>> {
-	let cache = download_cache.get_mut(block_id).expect("internal error");
-	let path = cache_dir.join(cache.id.to_string());
+fn cache_fetch(block_id: &BlockId, downloader: &mut Downloader) -> Result<Vec<u8>> {
+	let cache = downloader.download_cache.get_mut(block_id).expect("internal error");
+	let path = downloader.cache_dir.join(cache.id.to_string());
 
 	if cache.downloaded {
 		let plaintext = {
@@ -263,8 +702,8 @@ fn cache_fetch(block_id: &BlockId, keystore: &KeyStore, cache_dir: &Path, downlo
 
 		Ok(plaintext)
 	} else {
-		let encrypted_block = backend.fetch_block(&cache.id)?;
-		let plaintext = keystore.decrypt_block(&cache.id, &encrypted_block)?;
+		let encrypted_block = downloader.backend.fetch_block(&cache.id)?;
+		let plaintext = downloader.keystore.decrypt_block(&cache.id, &encrypted_block, downloader.compression)?;
 
 		cache.refcount -=1;
 		cache.downloaded = true;
@@ -279,18 +718,25 @@ fn cache_fetch(block_id: &BlockId, keystore: &KeyStore, cache_dir: &Path, downlo
 }
 
 
-fn set_file_time(path: &Path, mtime: i64, mtime_nsec: i64) -> Result<()> {
+/// Set the mtime of path (not following symlinks) to that of file.  The atime is set to the file's atime
+/// if restore_atime is true and the archive recorded one, otherwise to the mtime.
+fn set_file_time(path: &Path, file: &File, restore_atime: bool) -> Result<()> {
 	use std::ffi::CString;
 	use std::os::unix::prelude::*;
 	use libc::{time_t, timespec, utimensat, c_long, AT_FDCWD, AT_SYMLINK_NOFOLLOW};
 
+	let (atime, atime_nsec) = match file.atime {
+		Some(atime) if restore_atime => (atime, file.atime_nsec),
+		_ => (file.mtime, file.mtime_nsec),
+	};
+
 	let times = [timespec {
-		tv_sec: mtime as time_t,
-		tv_nsec: mtime_nsec as c_long,
+		tv_sec: atime as time_t,
+		tv_nsec: atime_nsec as c_long,
 	},
 	timespec {
-		tv_sec: mtime as time_t,
-		tv_nsec: mtime_nsec as c_long,
+		tv_sec: file.mtime as time_t,
+		tv_nsec: file.mtime_nsec as c_long,
 	}];
 	let p = CString::new(path.as_os_str().as_bytes()).expect("internal error");
 
@@ -302,3 +748,76 @@ fn set_file_time(path: &Path, mtime: i64, mtime_nsec: i64) -> Result<()> {
 		}
 	}
 }
+
+
+#[cfg(test)]
+mod test {
+	use super::{resolve_conflict, Config, ConflictPolicy, Resolution};
+	use crate::archive::File;
+	use std::fs;
+
+	fn archive_file(path: &str, is_dir: bool, mtime: i64) -> File {
+		File {
+			path: path.to_string(),
+			is_dir,
+			symlink: None,
+			hardlink_id: None,
+			mode: 0o644,
+			mtime,
+			mtime_nsec: 0,
+			atime: None,
+			atime_nsec: 0,
+			uid: 0,
+			gid: 0,
+			size: 0,
+			blocks: Vec::new(),
+			holes: Vec::new(),
+			xattrs: Default::default(),
+			special: None,
+		}
+	}
+
+	fn config(on_conflict: ConflictPolicy, existing_dirs_ok: bool) -> Config {
+		Config {
+			on_conflict,
+			existing_dirs_ok,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_resolve_conflict() {
+		let dir = tempfile::Builder::new().prefix("preserve-test-").tempdir().unwrap();
+		let existing = dir.path().join("file");
+		let missing = dir.path().join("missing");
+		let subdir = dir.path().join("subdir");
+		fs::write(&existing, b"existing").unwrap();
+		fs::create_dir(&subdir).unwrap();
+		let mtime = fs::metadata(&existing).unwrap().modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64;
+
+		let file = archive_file("file", false, mtime + 60);
+		let dir_file = archive_file("subdir", true, 0);
+
+		assert_eq!(resolve_conflict(&config(ConflictPolicy::Error, false), &missing, &file).unwrap(), Resolution::Create);
+		assert!(resolve_conflict(&config(ConflictPolicy::Error, false), &existing, &file).is_err());
+		assert!(resolve_conflict(&config(ConflictPolicy::Error, false), &subdir, &dir_file).is_err());
+		assert_eq!(resolve_conflict(&config(ConflictPolicy::Error, true), &subdir, &dir_file).unwrap(), Resolution::Merge);
+		assert_eq!(resolve_conflict(&config(ConflictPolicy::Skip, false), &existing, &file).unwrap(), Resolution::Skip);
+		// An existing directory is never replaced by a file
+		assert!(resolve_conflict(&config(ConflictPolicy::Overwrite, false), &subdir, &file).is_err());
+		assert_eq!(resolve_conflict(&config(ConflictPolicy::Overwrite, false), &subdir, &dir_file).unwrap(), Resolution::Merge);
+
+		// The existing file is newer than this one
+		let old_file = archive_file("file", false, mtime - 60);
+		assert_eq!(resolve_conflict(&config(ConflictPolicy::IfNewer, false), &existing, &old_file).unwrap(), Resolution::Skip);
+		assert!(existing.exists());
+		assert_eq!(resolve_conflict(&config(ConflictPolicy::IfNewer, false), &existing, &file).unwrap(), Resolution::Create);
+		assert!(!existing.exists());
+
+		fs::write(&existing, b"existing").unwrap();
+		fs::write(dir.path().join("file.~1~"), b"older").unwrap();
+		assert_eq!(resolve_conflict(&config(ConflictPolicy::Rename, false), &existing, &file).unwrap(), Resolution::Create);
+		assert!(!existing.exists());
+		assert_eq!(fs::read(dir.path().join("file.~2~")).unwrap(), b"existing");
+	}
+}
@@ -0,0 +1,232 @@
+use crate::backend::{Backend, FileBackend};
+use crate::backend::http::{ArchiveListing, ArchiveUpload};
+use crate::keystore::{ArchiveId, BlockId, EncryptedArchiveBlocklist, EncryptedArchiveMetadata, EncryptedArchiveName, EncryptedBlock};
+use crate::error::*;
+use clap::ArgMatches;
+use crypto::util::fixed_time_eq;
+use data_encoding::BASE64;
+use log::{error, info, warn};
+use std::fs;
+use std::io::{self, Read};
+use std::str::FromStr;
+use tiny_http::{Method, Request, Response, Server};
+
+
+/// Largest request body accepted.  Blocks are at most 64MB; archive metadata can be bigger, but not this big.
+const MAX_BODY_SIZE: u64 = 1024 * 1024 * 1024;
+
+
+pub fn execute(args: &ArgMatches) {
+	let root = args.value_of("root").expect("internal error");
+	let listen = args.value_of("listen").expect("internal error");
+	let args_token_file = args.value_of("token-file").expect("internal error");
+	let append_only = args.is_present("append-only");
+
+	let token = match fs::read_to_string(args_token_file) {
+		Ok(token) => token.trim().to_owned(),
+		Err(err) => {
+			error!("Unable to read token file: {}", err);
+			return;
+		}
+	};
+
+	if token.len() < 16 {
+		error!("The token must be at least 16 characters long");
+		return;
+	}
+
+	let server = match Server::http(listen) {
+		Ok(server) => server,
+		Err(err) => {
+			error!("Unable to listen on '{}': {}", listen, err);
+			return;
+		}
+	};
+
+	info!("Serving '{}' on {}{}", root, listen, if append_only { " (append-only)" } else { "" });
+
+	// One backend serves every request, one request at a time, so blocks buffered for a pack by one request are visible to the next.
+	serve(&server, FileBackend::new(root), &token, append_only);
+}
+
+
+/// Answer requests until the server is shut down.
+fn serve(server: &Server, mut backend: FileBackend, token: &str, append_only: bool) {
+	for mut request in server.incoming_requests() {
+		let response = if !authorized(&request, token) {
+			warn!("Rejected an unauthenticated request from {:?}", request.remote_addr());
+			Response::from_string("Bad token").with_status_code(401)
+		} else {
+			match handle(&mut backend, &mut request, append_only) {
+				Ok((status, data)) => Response::from_data(data).with_status_code(status),
+				Err(err) => {
+					let status = error_status(&err);
+
+					if status == 500 {
+						error!("{} {} failed: {}", request.method(), request.url(), err);
+					}
+
+					Response::from_string(err.to_string()).with_status_code(status)
+				},
+			}
+		};
+
+		if let Err(err) = request.respond(response) {
+			warn!("Unable to send a response: {}", err);
+		}
+	}
+}
+
+
+fn authorized(request: &Request, token: &str) -> bool {
+	let expected = format!("Bearer {}", token);
+
+	request.headers().iter()
+		.find(|header| header.field.equiv("Authorization"))
+		.map(|header| fixed_time_eq(header.value.as_str().as_bytes(), expected.as_bytes()))
+		.unwrap_or(false)
+}
+
+
+/// Returns the status and body of the response.
+fn handle(backend: &mut FileBackend, request: &mut Request, append_only: bool) -> Result<(u16, Vec<u8>)> {
+	let method = request.method().clone();
+	let url = request.url().to_owned();
+	// Nothing takes a query string, so it's ignored
+	let path: Vec<&str> = url.split('?').next().unwrap_or("").trim_matches('/').split('/').collect();
+
+	match (&method, &path[..]) {
+		(Method::Get, ["blocks"]) => Ok((200, serde_json::to_vec(&backend.list_blocks()?)?)),
+		(Method::Head, ["blocks", id]) => {
+			let status = if backend.block_exists(&BlockId::from_str(id)?)? { 200 } else { 404 };
+			Ok((status, Vec::new()))
+		},
+		(Method::Get, ["blocks", id]) => Ok((200, backend.fetch_block(&BlockId::from_str(id)?)?.0)),
+		(Method::Put, ["blocks", id]) => {
+			let block_id = BlockId::from_str(id)?;
+			let data = read_body(request)?;
+
+			backend.store_block(&block_id, &EncryptedBlock(data))?;
+			Ok((200, Vec::new()))
+		},
+		(Method::Delete, ["blocks", id]) => {
+			if append_only {
+				return Err(Error::AppendOnly);
+			}
+
+			backend.delete_block(&BlockId::from_str(id)?)?;
+			Ok((200, Vec::new()))
+		},
+		(Method::Get, ["archives"]) => {
+			let listings: Vec<ArchiveListing> = backend.list_archives()?.into_iter().map(|(id, name)| ArchiveListing {
+				id,
+				name: BASE64.encode(&name.0),
+			}).collect();
+
+			Ok((200, serde_json::to_vec(&listings)?))
+		},
+		(Method::Put, ["archives", id]) => {
+			let archive_id = archive_id(id)?;
+			let upload: ArchiveUpload = serde_json::from_slice(&read_body(request)?)?;
+			let decode = |s: &str| BASE64.decode(s.as_bytes()).map_err(|err| Error::RemoteBackend(format!("Invalid archive upload: {}", err)));
+
+			backend.store_archive(&archive_id, &EncryptedArchiveName(decode(&upload.name)?), &EncryptedArchiveBlocklist(decode(&upload.blocklist)?), &EncryptedArchiveMetadata(decode(&upload.metadata)?))?;
+			Ok((200, Vec::new()))
+		},
+		(Method::Delete, ["archives", id]) => {
+			if append_only {
+				return Err(Error::AppendOnly);
+			}
+
+			backend.delete_archive(&archive_id(id)?)?;
+			Ok((200, Vec::new()))
+		},
+		(Method::Get, ["archives", id, "metadata"]) => Ok((200, backend.fetch_archive(&archive_id(id)?)?.0)),
+		(Method::Get, ["archives", id, "blocklist"]) => match backend.fetch_archive_blocklist(&archive_id(id)?)? {
+			Some(blocklist) => Ok((200, blocklist.0)),
+			None => Ok((404, Vec::new())),
+		},
+		// Allowed in append-only mode.  Nothing can be deleted there, so flushing only writes out buffered blocks.
+		(Method::Post, ["flush"]) => {
+			backend.flush()?;
+			Ok((200, Vec::new()))
+		},
+		_ => Ok((404, format!("Unknown request: {} {}", method, url).into_bytes())),
+	}
+}
+
+
+fn archive_id(s: &str) -> Result<ArchiveId> {
+	ArchiveId::from_str(s).map_err(|_| Error::InvalidArchiveId)
+}
+
+
+fn read_body(request: &mut Request) -> Result<Vec<u8>> {
+	let mut data = Vec::new();
+	request.as_reader().take(MAX_BODY_SIZE + 1).read_to_end(&mut data)?;
+
+	if data.len() as u64 > MAX_BODY_SIZE {
+		return Err(Error::RemoteBackend("Request body is too large".to_string()));
+	}
+
+	Ok(data)
+}
+
+
+fn error_status(err: &Error) -> u16 {
+	match *err {
+		Error::BlockNotFound | Error::ArchiveNotFound => 404,
+		Error::Io(ref err) if err.kind() == io::ErrorKind::NotFound => 404,
+		Error::ArchiveNameConflict | Error::BlockReferenced => 409,
+		Error::AppendOnly => 403,
+		Error::InvalidArchiveId | Error::InvalidBlockId | Error::Json(_) | Error::RemoteBackend(_) => 400,
+		_ => 500,
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::serve;
+	use crate::backend::{Backend, FileBackend, HttpBackend};
+	use crate::backend::test::exercise_backend;
+	use crate::keystore::{BlockId, EncryptedBlock};
+	use std::io::{Read, Write};
+	use std::net::TcpStream;
+	use std::thread;
+	use tiny_http::Server;
+	use url::Url;
+
+	#[test]
+	fn test_serve() {
+		let dir = tempfile::Builder::new().prefix("preserve-test-").tempdir().unwrap();
+		let server = Server::http("127.0.0.1:0").unwrap();
+		let port = server.server_addr().to_ip().unwrap().port();
+		let backend = FileBackend::new(dir.path());
+
+		// The server runs until the test process exits
+		thread::spawn(move || serve(&server, backend, "0123456789abcdef", false));
+
+		let mut backend = HttpBackend::new(&Url::parse(&format!("http://:0123456789abcdef@127.0.0.1:{}/", port)).unwrap()).unwrap();
+		exercise_backend(&mut backend);
+
+		// Query strings are ignored
+		let block_id = BlockId::from_slice(&[2; 32]).unwrap();
+		backend.store_block(&block_id, &EncryptedBlock(vec![2, 3, 4])).unwrap();
+		backend.flush().unwrap();
+
+		let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+		write!(stream, "GET /blocks/{}?x=1 HTTP/1.0\r\nAuthorization: Bearer 0123456789abcdef\r\n\r\n", block_id.to_string()).unwrap();
+		let mut response = Vec::new();
+		stream.read_to_end(&mut response).unwrap();
+		assert!(response.starts_with(b"HTTP/1.0 200 "));
+		assert!(response.ends_with(&[2, 3, 4]));
+
+		// With the wrong path every request is a 404, which must not look like a successful store
+		let mut backend = HttpBackend::new(&Url::parse(&format!("http://:0123456789abcdef@127.0.0.1:{}/preserve/", port)).unwrap()).unwrap();
+		let block_id = BlockId::from_slice(&[1; 32]).unwrap();
+		assert!(!backend.block_exists(&block_id).unwrap());
+		assert!(backend.store_block(&block_id, &EncryptedBlock(vec![1, 2, 3])).is_err());
+		assert!(backend.flush().is_err());
+	}
+}
@@ -2,9 +2,10 @@ use crate::keystore::{KeyStore, BlockId};
 use std::collections::HashSet;
 use crate::backend::{self, Backend};
 use crate::archive::{Archive, File};
+use crate::compression::Compression;
 use rand::prelude::*;
 use clap::ArgMatches;
-use log::{error, info};
+use log::{error, info, warn};
 
 
 pub fn execute(args: &ArgMatches) {
@@ -44,9 +45,13 @@ pub fn execute(args: &ArgMatches) {
 		}
 	};
 
-	if archive.version != 0x00000001 {
-		error!("Unsupported archive version");
-		return;
+	match backend.fetch_archive_blocklist(&archive_id) {
+		Ok(Some(encrypted_blocklist)) => match archive.verify_blocklist(&archive_id, &encrypted_blocklist, &keystore) {
+			Ok(_) => info!("The archive's blocklist is intact"),
+			Err(err) => error!("CRITICAL ERROR: {}", err),
+		},
+		Ok(None) => warn!("This archive does not have a blocklist.  It was probably created by an older version of preserve."),
+		Err(err) => error!("A problem occured while fetching the archive's blocklist: {}", err),
 	}
 
 	let mut block_list = HashSet::new();
@@ -57,7 +62,7 @@ pub fn execute(args: &ArgMatches) {
 	// probablistically cover all blocks.
 	block_list.shuffle(&mut rand::thread_rng());
 
-	verify_blocks(&block_list, &keystore, &mut *backend);
+	verify_blocks(&block_list, archive.compression(), &keystore, &mut *backend);
 }
 
 
@@ -70,7 +75,7 @@ fn build_block_list(files: &[File], block_list: &mut HashSet<BlockId>) {
 }
 
 
-fn verify_blocks(block_list: &[BlockId], keystore: &KeyStore, backend: &mut dyn Backend) {
+fn verify_blocks(block_list: &[BlockId], compression: Compression, keystore: &KeyStore, backend: &mut dyn Backend) {
 	let mut corrupted_blocks = Vec::new();
 
 	for (idx, block_id) in block_list.iter().enumerate() {
@@ -83,7 +88,7 @@ fn verify_blocks(block_list: &[BlockId], keystore: &KeyStore, backend: &mut dyn
 			}
 		};
 
-		if keystore.decrypt_block(&block_id, &encrypted_block).is_err() {
+		if keystore.decrypt_block(&block_id, &encrypted_block, compression).is_err() {
 			error!("CRITICAL ERROR: Block {} is corrupt.  You should save a copy of the corrupted block, delete it, and then rearchive the files that created this archive.  That should recreate the block.", block_id.to_string());
 			corrupted_blocks.push(block_id.to_string());
 		}
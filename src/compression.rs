@@ -0,0 +1,114 @@
+use crate::error::*;
+use serde_derive::{Deserialize, Serialize};
+
+
+/// Codec used to compress blocks before they are encrypted.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+	#[default]
+	None,
+	Zstd,
+	Lz4,
+	Lzma,
+}
+
+const ZSTD_LEVEL: i32 = 3;
+const LZMA_PRESET: u32 = 6;
+
+
+impl Compression {
+	pub fn from_name(name: &str) -> Option<Compression> {
+		match name {
+			"none" => Some(Compression::None),
+			"zstd" => Some(Compression::Zstd),
+			"lz4" => Some(Compression::Lz4),
+			"lzma" => Some(Compression::Lzma),
+			_ => None,
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Compression::None => "none",
+			Compression::Zstd => "zstd",
+			Compression::Lz4 => "lz4",
+			Compression::Lzma => "lzma",
+		}
+	}
+
+	/// Identifies the codec in the first byte of a compressed block
+	fn tag(self) -> u8 {
+		match self {
+			Compression::None => 0,
+			Compression::Zstd => 1,
+			Compression::Lz4 => 2,
+			Compression::Lzma => 3,
+		}
+	}
+}
+
+
+/// Compress data, returning the codec's tag followed by the compressed data.
+/// Returns None if compression is disabled, fails, or wouldn't make the data smaller.
+pub fn compress(compression: Compression, data: &[u8]) -> Option<Vec<u8>> {
+	let compressed = match compression {
+		Compression::None => return None,
+		Compression::Zstd => zstd::encode_all(data, ZSTD_LEVEL).ok()?,
+		Compression::Lz4 => lz4::block::compress(data, None, true).ok()?,
+		Compression::Lzma => lzma::compress(data, LZMA_PRESET).ok()?,
+	};
+
+	if compressed.len() + 1 >= data.len() {
+		return None;
+	}
+
+	let mut result = Vec::with_capacity(compressed.len() + 1);
+	result.push(compression.tag());
+	result.extend_from_slice(&compressed);
+
+	Some(result)
+}
+
+
+/// The inverse of compress.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+	let (&tag, compressed) = data.split_first().ok_or(Error::CorruptBlockFailedDecompression)?;
+
+	let decompressed = if tag == Compression::Zstd.tag() {
+		zstd::decode_all(compressed).ok()
+	} else if tag == Compression::Lz4.tag() {
+		lz4::block::decompress(compressed, None).ok()
+	} else if tag == Compression::Lzma.tag() {
+		lzma::decompress(compressed).ok()
+	} else {
+		None
+	};
+
+	decompressed.ok_or(Error::CorruptBlockFailedDecompression)
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::{compress, decompress, Compression};
+
+	#[test]
+	fn test_round_trip() {
+		let data = "All work and no play makes Jack a dull boy.\n".repeat(1000);
+
+		for &compression in &[Compression::Zstd, Compression::Lz4, Compression::Lzma] {
+			// lzma may not actually compress in every build, in which case there's nothing to decompress
+			if let Some(compressed) = compress(compression, data.as_bytes()) {
+				assert!(compressed.len() < data.len());
+				assert_eq!(decompress(&compressed).unwrap(), data.as_bytes());
+			}
+		}
+
+		assert!(compress(Compression::Zstd, data.as_bytes()).is_some());
+		assert_eq!(compress(Compression::None, data.as_bytes()), None);
+		// Incompressible data is left alone
+		assert_eq!(compress(Compression::Zstd, b"x"), None);
+		assert!(decompress(&[42, 1, 2, 3]).is_err());
+	}
+}
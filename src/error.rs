@@ -18,15 +18,29 @@ pub enum Error {
 	CorruptArchiveName,
 	CorruptArchiveFailedDecompression,
 	CorruptArchiveBadJson,
+	UnsupportedArchiveVersion,
 	CorruptBlock,
+	CorruptBlockFailedDecompression,
 	CorruptKeystore,
 	CorruptArchiveMetadata,
+	CorruptArchiveBlocklist,
+	CorruptArchiveLog,
 	ArchiveNameConflict,
 	BlockNotFound,
 	ArchiveNotFound,
 	InvalidArchiveName,
 	InvalidArchiveId,
+	InvalidBlockId,
 	BackendOnDifferentDevices,
+	CorruptPackIndex,
+	BlockReferenced,
+	/// A request to a remote backend failed
+	#[cfg(any(feature = "s3", feature = "http"))]
+	RemoteBackend(String),
+	/// The backend refused to delete or overwrite something because it is append-only
+	AppendOnly,
+	/// Every backend being mirrored to has failed
+	NoBackendsLeft,
 	Sqlite(SqliteError),
 }
 
@@ -45,15 +59,26 @@ impl StdError for Error {
 			CorruptArchiveName => "The encrypted archive name is corrupted",
 			CorruptArchiveFailedDecompression => "The encrypted archive is corrupt: could not be decompressed",
 			CorruptArchiveBadJson => "The encrypted archive is corrupt: the internal JSON data is invalid",
+			UnsupportedArchiveVersion => "The archive was created by a newer version of preserve and is not supported",
 			CorruptBlock => "The encrypted block is corrupted",
+			CorruptBlockFailedDecompression => "The encrypted block is corrupt: could not be decompressed",
 			CorruptKeystore => "The keystore is corrupted",
 			CorruptArchiveMetadata => "The archive metadata is corrupted",
+			CorruptArchiveBlocklist => "The archive blocklist is corrupted or does not match the archive",
+			CorruptArchiveLog => "The archive log is corrupted, was tampered with, or belongs to a different keyfile",
 			BlockNotFound => "The specified block was not found",
 			ArchiveNotFound => "The specified archive was not found",
 			InvalidArchiveName => "An invalid archive name was encountered.  Possibly a stray file.",
 			InvalidArchiveId => "An invalid archive id was encountered.  Possibly a stray file.",
+			InvalidBlockId => "An invalid block id was encountered.  Possibly a stray file.",
 			ArchiveNameConflict => "An archive with that name already exists",
 			BackendOnDifferentDevices => "All folders in the backend must be on the same drive",
+			CorruptPackIndex => "A pack index in the backend is corrupted",
+			BlockReferenced => "The block is still referenced by an archive",
+			#[cfg(any(feature = "s3", feature = "http"))]
+			RemoteBackend(ref e) => e,
+			AppendOnly => "The backend is append-only",
+			NoBackendsLeft => "All of the backends have failed",
 			Sqlite(ref e) => e.description(),
 		}
 	}
@@ -66,15 +91,26 @@ impl StdError for Error {
 			CorruptArchiveName => None,
 			CorruptArchiveFailedDecompression => None,
 			CorruptArchiveBadJson => None,
+			UnsupportedArchiveVersion => None,
 			CorruptBlock => None,
+			CorruptBlockFailedDecompression => None,
 			CorruptKeystore => None,
 			CorruptArchiveMetadata => None,
+			CorruptArchiveBlocklist => None,
+			CorruptArchiveLog => None,
 			ArchiveNameConflict => None,
 			BlockNotFound => None,
 			InvalidArchiveName => None,
 			InvalidArchiveId => None,
+			InvalidBlockId => None,
 			ArchiveNotFound => None,
 			BackendOnDifferentDevices => None,
+			CorruptPackIndex => None,
+			BlockReferenced => None,
+			#[cfg(any(feature = "s3", feature = "http"))]
+			RemoteBackend(_) => None,
+			AppendOnly => None,
+			NoBackendsLeft => None,
 			Sqlite(ref error) => Some(error),
 		}
 	}
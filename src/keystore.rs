@@ -7,6 +7,7 @@ use crypto::mac::Mac;
 use crypto::symmetriccipher::SynchronousStreamCipher;
 use std::str::FromStr;
 use crate::error::*;
+use crate::compression::{self, Compression};
 use std::path::Path;
 use std::fs;
 use std::convert::TryFrom;
@@ -20,6 +21,11 @@ new_type!{ public BlockId(32); }
 new_type!{ public ArchiveId(32); }
 new_type!{ public SIV(32); }
 
+
+/// Associated data for compressed blocks, which tells them apart from uncompressed blocks.
+const COMPRESSED_BLOCK_AD: &[u8] = b"compressed";
+
+
 impl ToString for BlockId {
 	fn to_string(&self) -> String {
 		HEXLOWER_PERMISSIVE.encode(&self.0)
@@ -32,6 +38,16 @@ impl ToString for ArchiveId {
 	}
 }
 
+impl FromStr for BlockId {
+	type Err = Error;
+
+	fn from_str(s: &str) -> ::std::result::Result<BlockId, Self::Err> {
+		let v = HEXLOWER_PERMISSIVE.decode(s.as_bytes()).map_err(|_| Error::InvalidBlockId)?;
+
+		BlockId::from_slice(&v).ok_or(Error::InvalidBlockId)
+	}
+}
+
 impl FromStr for ArchiveId {
 	type Err = Error;
 
@@ -45,6 +61,23 @@ impl FromStr for ArchiveId {
 pub struct EncryptedArchiveName(pub Vec<u8>);
 pub struct EncryptedBlock(pub Vec<u8>);
 pub struct EncryptedArchiveMetadata(pub Vec<u8>);
+/// BlocklistId || Blocklist.  The Blocklist itself is plaintext (so backends can use it for refcounting) and authenticated by BlocklistId.
+pub struct EncryptedArchiveBlocklist(pub Vec<u8>);
+
+impl EncryptedArchiveBlocklist {
+	/// Parse the list of BlockIds without authenticating it.
+	/// This does not need the keystore, so backends and offline tools can use it to find out which blocks an archive references.
+	/// Anything that needs to trust the result should use KeyStore::decrypt_archive_blocklist instead.
+	pub fn unauthenticated_block_ids(&self) -> Result<Vec<BlockId>> {
+		let block_ids = self.0.get(32..).ok_or(Error::CorruptArchiveBlocklist)?.chunks_exact(32);
+
+		if !block_ids.remainder().is_empty() {
+			return Err(Error::CorruptArchiveBlocklist);
+		}
+
+		Ok(block_ids.map(|chunk| BlockId::from_slice(chunk).expect("internal error")).collect())
+	}
+}
 
 
 #[derive(PartialEq, Clone)]
@@ -131,6 +164,10 @@ pub struct KeyStore {
 	archive_name_keys: SivEncryptionKeys,
 	blocklist_keys: SivEncryptionKeys,
 	metadata_keys: SivEncryptionKeys,
+	/// Keys the content defined chunker, so chunk boundaries (and thus block sizes) don't reveal anything about the content.
+	chunker_key: HmacKey,
+	/// Authenticates the entries of archive logs.
+	archive_log_key: HmacKey,
 }
 
 impl KeyStore {
@@ -146,7 +183,7 @@ impl KeyStore {
 	/// to derive all the other keys in the KeyStore.
 	pub fn from_master_key(master_key: HmacKey) -> KeyStore {
 		let raw_keys = {
-			let mut raw_keys = vec![0u8; 4 * 256];
+			let mut raw_keys = vec![0u8; 4 * 256 + 2 * 128];
 			let mut hmac = Hmac::new(Sha512::new(), &master_key[..]);
 			pbkdf2(&mut hmac, &[], 1, &mut raw_keys);
 			raw_keys
@@ -155,7 +192,9 @@ impl KeyStore {
 		let (block_keys, raw_keys) = raw_keys.split_at(256);
 		let (archive_name_keys, raw_keys) = raw_keys.split_at(256);
 		let (blocklist_keys, raw_keys) = raw_keys.split_at(256);
-		let (metadata_keys, _) = raw_keys.split_at(256);
+		let (metadata_keys, raw_keys) = raw_keys.split_at(256);
+		let (chunker_key, raw_keys) = raw_keys.split_at(128);
+		let (archive_log_key, _) = raw_keys.split_at(128);
 
 		KeyStore {
 			master_key,
@@ -164,6 +203,8 @@ impl KeyStore {
 			archive_name_keys: SivEncryptionKeys::from_slice(archive_name_keys).expect("internal error"),
 			blocklist_keys: SivEncryptionKeys::from_slice(blocklist_keys).expect("internal error"),
 			metadata_keys: SivEncryptionKeys::from_slice(metadata_keys).expect("internal error"),
+			chunker_key: HmacKey::from_slice(chunker_key).expect("internal error"),
+			archive_log_key: HmacKey::from_slice(archive_log_key).expect("internal error"),
 		}
 	}
 
@@ -191,14 +232,61 @@ impl KeyStore {
 		KeyStore::load(&mut reader)
 	}
 
-	pub fn encrypt_block(&self, block: &[u8]) -> (BlockId, EncryptedBlock) {
-		let (id, ciphertext) = self.block_keys.encrypt(&[], block);
+	/// The 256 entry gear table used by the content defined chunker.
+	/// Entry i is bytes [8*i, 8*i+8) (little endian) of HMAC-SHA-512 (chunker_key, 0) || HMAC-SHA-512 (chunker_key, 1) || ...
+	pub fn chunker_gear_table(&self) -> [u64; 256] {
+		let mut table = [0u64; 256];
+
+		for (counter, entries) in table.chunks_mut(8).enumerate() {
+			let mut hmac = Hmac::new(Sha512::new(), &self.chunker_key[..]);
+			hmac.input(&[counter as u8]);
+			let result = hmac.result();
+
+			for (entry, bytes) in entries.iter_mut().zip(result.code().chunks(8)) {
+				*entry = u64::from_le_bytes(<[u8; 8]>::try_from(bytes).expect("internal error"));
+			}
+		}
+
+		table
+	}
+
+	/// MAC for an archive log entry, chaining it to the entry before it (or 32 zero bytes for the first entry).
+	/// HMAC-SHA-512-256 (archive_log_key, previous_mac || archive_id || encrypted_name)
+	pub fn archive_log_mac(&self, previous_mac: &[u8], archive_id: &ArchiveId, encrypted_name: &EncryptedArchiveName) -> Vec<u8> {
+		let mut hmac = Hmac::new(Sha512::new(), &self.archive_log_key[..]);
+		hmac.input(previous_mac);
+		hmac.input(&archive_id[..]);
+		hmac.input(&encrypted_name.0);
+
+		hmac.result().code()[..32].to_vec()
+	}
+
+	/// The block is compressed first, unless compression is disabled or doesn't make it smaller.
+	/// Uncompressed blocks are encrypted just like they were before compression was supported, so they keep the same BlockIds.
+	/// Compressed blocks are encrypted with COMPRESSED_BLOCK_AD, and their codec is part of the plaintext.
+	pub fn encrypt_block(&self, block: &[u8], compression: Compression) -> (BlockId, EncryptedBlock) {
+		let (id, ciphertext) = match compression::compress(compression, block) {
+			Some(compressed) => self.block_keys.encrypt(COMPRESSED_BLOCK_AD, &compressed),
+			None => self.block_keys.encrypt(&[], block),
+		};
 
 		(BlockId(id.0), EncryptedBlock(ciphertext))
 	}
 
-	pub fn decrypt_block(&self, block_id: &BlockId, encrypted_block: &EncryptedBlock) -> Result<Vec<u8>> {
-		self.block_keys.decrypt(&[], &SIV(block_id.clone().0), &encrypted_block.0).ok_or(Error::CorruptBlock)
+	/// Returns the original, decompressed, block.  compression is the codec of the archive the block belongs to, and only decides
+	/// which kind of block is tried first: blocks are shared between archives, so it may have been stored either way.
+	pub fn decrypt_block(&self, block_id: &BlockId, encrypted_block: &EncryptedBlock, compression: Compression) -> Result<Vec<u8>> {
+		let siv = SIV(block_id.clone().0);
+		let decrypt_compressed = || self.block_keys.decrypt(COMPRESSED_BLOCK_AD, &siv, &encrypted_block.0).map(|compressed| compression::decompress(&compressed));
+		let decrypt_uncompressed = || self.block_keys.decrypt(&[], &siv, &encrypted_block.0).map(Ok);
+
+		let plaintext = if compression == Compression::None {
+			decrypt_uncompressed().or_else(decrypt_compressed)
+		} else {
+			decrypt_compressed().or_else(decrypt_uncompressed)
+		};
+
+		plaintext.unwrap_or(Err(Error::CorruptBlock))
 	}
 
 	pub fn encrypt_archive_name(&self, name: &str) -> (ArchiveId, EncryptedArchiveName) {
@@ -223,6 +311,30 @@ impl KeyStore {
 		EncryptedArchiveMetadata(result)
 	}
 
+	/// Blocklist authentication is SivEncrypt (blocklist_keys, ArchiveId || Blocklist, []).  Nothing is actually encrypted; the SIV
+	/// is just a MAC binding the plaintext Blocklist to the archive.
+	pub fn encrypt_archive_blocklist(&self, archive_id: &ArchiveId, blocklist: &[BlockId]) -> EncryptedArchiveBlocklist {
+		let encoded_blocklist: Vec<u8> = blocklist.iter().flat_map(|block_id| block_id[..].to_vec()).collect();
+		let aad = [&archive_id[..], &encoded_blocklist[..]].concat();
+		let (blocklist_siv, _) = self.blocklist_keys.encrypt(&aad, &[]);
+		let mut result = Vec::new();
+
+		result.extend_from_slice(&blocklist_siv[..]);
+		result.extend_from_slice(&encoded_blocklist);
+
+		EncryptedArchiveBlocklist(result)
+	}
+
+	pub fn decrypt_archive_blocklist(&self, archive_id: &ArchiveId, encrypted_blocklist: &EncryptedArchiveBlocklist) -> Result<Vec<BlockId>> {
+		let blocklist = encrypted_blocklist.unauthenticated_block_ids()?;
+		let (siv, encoded_blocklist) = encrypted_blocklist.0.split_at(32);
+		let aad = [&archive_id[..], encoded_blocklist].concat();
+
+		self.blocklist_keys.decrypt(&aad, &SIV::from_slice(siv).expect("internal error"), &[]).ok_or(Error::CorruptArchiveBlocklist)?;
+
+		Ok(blocklist)
+	}
+
 	pub fn decrypt_archive_metadata(&self, archive_id: &ArchiveId, encrypted_metadata: &EncryptedArchiveMetadata) -> Result<Vec<u8>> {
 		if encrypted_metadata.0.len() < 32 {
 			return Err(Error::CorruptArchiveMetadata);
@@ -241,6 +353,7 @@ impl KeyStore {
 #[cfg(test)]
 mod test {
 	use super::{HmacKey, SivEncryptionKeys, KeyStore, SIV};
+	use crate::compression::Compression;
 	use crypto::pbkdf2::pbkdf2;
 	use crypto::hmac::Hmac;
 	use crypto::sha2::Sha512;
@@ -404,14 +517,23 @@ mod test {
 		let keystore = KeyStore::new();
 		let test_data = "just plain old data";
 
-		let (block_id, mut block_ciphertext) = keystore.encrypt_block(test_data.as_bytes());
+		let (block_id, mut block_ciphertext) = keystore.encrypt_block(test_data.as_bytes(), Compression::None);
 		let (archive_id, name_ciphertext) = keystore.encrypt_archive_name(test_data);
 		let metadata_ciphertext = keystore.encrypt_archive_metadata(&archive_id, test_data.as_bytes());
+		let mut blocklist_ciphertext = keystore.encrypt_archive_blocklist(&archive_id, &[block_id]);
 
 		// Decryption should work
-		assert_eq!(test_data.as_bytes(), &keystore.decrypt_block(&block_id, &block_ciphertext).unwrap()[..]);
+		assert_eq!(test_data.as_bytes(), &keystore.decrypt_block(&block_id, &block_ciphertext, Compression::None).unwrap()[..]);
 		assert_eq!(test_data, keystore.decrypt_archive_name(&archive_id, &name_ciphertext).unwrap());
 		assert_eq!(test_data.as_bytes(), &keystore.decrypt_archive_metadata(&archive_id, &metadata_ciphertext).unwrap()[..]);
+		assert_eq!(vec![block_id], keystore.decrypt_archive_blocklist(&archive_id, &blocklist_ciphertext).unwrap());
+
+		// The blocklist is readable without keys, but only authenticates against the archive it was made for
+		assert_eq!(vec![block_id], blocklist_ciphertext.unauthenticated_block_ids().unwrap());
+		let (other_archive_id, _) = keystore.encrypt_archive_name("other archive");
+		assert!(keystore.decrypt_archive_blocklist(&other_archive_id, &blocklist_ciphertext).is_err());
+		blocklist_ciphertext.0[40] ^= 0xbe;
+		assert!(keystore.decrypt_archive_blocklist(&archive_id, &blocklist_ciphertext).is_err());
 
 		// Even when the data is the same, every type of object should get different IDs because different keys are used
 		assert_ne!(&block_id[..], &archive_id[..]);
@@ -419,7 +541,7 @@ mod test {
 
 		// Decryption should fail if ciphertext is modified
 		block_ciphertext.0[0] ^= 0xbe;
-		assert!(keystore.decrypt_block(&block_id, &block_ciphertext).is_err());
+		assert!(keystore.decrypt_block(&block_id, &block_ciphertext, Compression::None).is_err());
 
 		// Make sure encrypting unicode names works
 		let unicode_name = "(╯°□°）╯︵ ┻━┻";
@@ -448,14 +570,15 @@ mod test {
 		let keystore = KeyStore::new();
 		let test_data = "just plain old data";
 
-		let (block_id, block_ciphertext) = keystore.encrypt_block(test_data.as_bytes());
+		let (block_id, block_ciphertext) = keystore.encrypt_block(test_data.as_bytes(), Compression::None);
 		let (archive_id, name_ciphertext) = keystore.encrypt_archive_name(test_data);
 		let metadata_ciphertext = keystore.encrypt_archive_metadata(&archive_id, test_data.as_bytes());
+		let blocklist_ciphertext = keystore.encrypt_archive_blocklist(&archive_id, &[block_id]);
 
 		// Now try to decrypt, but corrupt all the other keys that shouldn't be used.  If the system is using the right key, that decryption should still be successful.
 		let mut modified_keystore = KeyStore::new();
 		modified_keystore.block_keys = keystore.block_keys.clone();
-		assert_eq!(test_data.as_bytes(), &modified_keystore.decrypt_block(&block_id, &block_ciphertext).unwrap()[..]);
+		assert_eq!(test_data.as_bytes(), &modified_keystore.decrypt_block(&block_id, &block_ciphertext, Compression::None).unwrap()[..]);
 
 		let mut modified_keystore = KeyStore::new();
 		modified_keystore.archive_name_keys = keystore.archive_name_keys.clone();
@@ -464,5 +587,28 @@ mod test {
 		let mut modified_keystore = KeyStore::new();
 		modified_keystore.metadata_keys = keystore.metadata_keys.clone();
 		assert_eq!(test_data.as_bytes(), &modified_keystore.decrypt_archive_metadata(&archive_id, &metadata_ciphertext).unwrap()[..]);
+
+		let mut modified_keystore = KeyStore::new();
+		modified_keystore.blocklist_keys = keystore.blocklist_keys.clone();
+		assert_eq!(vec![block_id], modified_keystore.decrypt_archive_blocklist(&archive_id, &blocklist_ciphertext).unwrap());
+	}
+
+	#[test]
+	fn test_compressed_blocks() {
+		let keystore = KeyStore::new();
+		let test_data = "just plain old data ".repeat(100);
+
+		let (block_id, block_ciphertext) = keystore.encrypt_block(test_data.as_bytes(), Compression::Zstd);
+		let (uncompressed_block_id, _) = keystore.encrypt_block(test_data.as_bytes(), Compression::None);
+
+		// decrypt_block handles both compressed and uncompressed blocks, whichever codec the archive uses
+		assert!(block_ciphertext.0.len() < test_data.len());
+		assert_ne!(block_id, uncompressed_block_id);
+		assert_eq!(test_data.as_bytes(), &keystore.decrypt_block(&block_id, &block_ciphertext, Compression::Zstd).unwrap()[..]);
+		assert_eq!(test_data.as_bytes(), &keystore.decrypt_block(&block_id, &block_ciphertext, Compression::None).unwrap()[..]);
+
+		// Blocks which don't shrink are stored uncompressed, with the same BlockId as always
+		let (block_id, _) = keystore.encrypt_block(b"tiny", Compression::Zstd);
+		assert_eq!(block_id, keystore.encrypt_block(b"tiny", Compression::None).0);
 	}
 }
@@ -2,17 +2,23 @@
 mod newtype_macros;
 mod keystore;
 mod archive;
+mod archive_log;
+mod chunker;
+mod compression;
 mod backend;
 mod cmds;
 mod logger;
 mod error;
+mod sparse;
+mod users;
+mod xattrs;
 
 use crate::logger::Logger;
 use clap::{App, AppSettings, SubCommand, Arg, crate_version};
 
 
 fn main() {
-	let matches = App::new("preserve")
+	let app = App::new("preserve")
                         .version(crate_version!())
                         .about("Robust, Encrypted Backup")
 						.setting(AppSettings::SubcommandRequiredElseHelp)
@@ -28,11 +34,28 @@ fn main() {
 							.setting(AppSettings::ColoredHelp)
                             .args_from_usage(
 								"--keyfile=<KEYFILE>  'Sets the keyfile to use'
-								 --backend=<BACKEND>  'Sets the backend to use'
 								 --dereference        'Follow symlinks'
 								 --one-file-system    'Ignore things on other filesystems'
+								 --archive-log=[FILE] 'Also record the backup in this archive log (see audit)'
 								 <NAME>               'Unique name for this backup'
 								 <PATH>               'The path to backup'")
+							.arg(
+								Arg::with_name("backend")
+									.long("backend")
+									.takes_value(true)
+									.required(true)
+									.multiple(true)
+									.number_of_values(1)
+									.help("Sets the backend to use.  Give it more than once to store the backup on several backends")
+							)
+							.arg(
+								Arg::with_name("on-backend-failure")
+									.long("on-backend-failure")
+									.takes_value(true)
+									.possible_values(&["warn", "fail"])
+									.default_value("fail")
+									.help("What to do if one of several backends fails: carry on with the others, or stop")
+							)
 							.arg(
 								Arg::with_name("exclude")
 									.long("exclude")
@@ -41,6 +64,37 @@ fn main() {
 									.number_of_values(1)
 									.help("Exclude the given path")
 							)
+							.arg(
+								Arg::with_name("chunker")
+									.long("chunker")
+									.takes_value(true)
+									.possible_values(&["fastcdc", "fixed"])
+									.default_value("fixed")
+									.help("How files are split into blocks.  fastcdc finds boundaries from the content, so inserting data into a file only changes nearby blocks")
+							)
+							.arg(
+								Arg::with_name("chunk-size")
+									.long("chunk-size")
+									.takes_value(true)
+									.value_name("SIZES")
+									.help("Block sizes in bytes (K and M suffixes allowed).  MIN,AVG,MAX for fastcdc (default 256K,1M,4M), SIZE for fixed (default 1M)")
+							)
+							.arg(
+								Arg::with_name("compression")
+									.long("compression")
+									.takes_value(true)
+									.possible_values(&["none", "zstd", "lz4", "lzma"])
+									.default_value("none")
+									.help("Compress blocks before encrypting them.  Blocks that don't get smaller are stored uncompressed")
+							)
+							.arg(
+								Arg::with_name("xattr-namespaces")
+									.long("xattr-namespaces")
+									.takes_value(true)
+									.value_name("NAMESPACES")
+									.default_value(xattrs::DEFAULT_NAMESPACES)
+									.help("Comma separated list of extended attribute namespaces to back up (security, system, trusted, user), or 'none'")
+							)
 						)
 						.subcommand(SubCommand::with_name("keygen")
 							.about("create a new keyfile")
@@ -55,7 +109,20 @@ fn main() {
 							.setting(AppSettings::ColoredHelp)
                             .args_from_usage(
 								"--keyfile=<KEYFILE>  'Sets the keyfile to use'
-								 --backend=<BACKEND>  'Sets the backend to use'")
+								 --backend=<BACKEND>  'Sets the backend to use'
+								 -l, --long           'Show creation time, file count, total size and original path of each backup'
+								 --json               'Output JSON (includes everything shown by --long)'
+								 --name=[GLOB]        'Only list backups whose name matches GLOB'
+								 --after=[DATE]       'Only list backups created at or after DATE (YYYY-MM-DD or \'YYYY-MM-DD HH:MM:SS\', local time)'
+								 --before=[DATE]      'Only list backups created before DATE'")
+							.arg(
+								Arg::with_name("sort")
+									.long("sort")
+									.takes_value(true)
+									.possible_values(&["name", "time"])
+									.default_value("name")
+									.help("Sort backups by name or creation time")
+							)
 						)
 						.subcommand(SubCommand::with_name("restore")
 							.about("restore an existing backup")
@@ -66,8 +133,46 @@ fn main() {
 								 --backend=<BACKEND>  'Sets the backend to use'
 								 --hard-dereference   'Dereference hardlinks'
 								 --debug-decrypt      'Just fetch and decrypt the archive; no decompression, parsing, or extraction'
+								 --existing-dirs-ok   'Restore into directories that already exist instead of treating them as conflicts'
+								 --resume             'Continue an interrupted restore: skip files that already exist with the right size and mtime'
+								 --check-content      'With --resume, also check that the contents of existing files match the backup'
+								 --no-same-owner      'Don\'t restore the owner and group of files'
+								 --numeric-owner      'Restore owners by the uid/gid recorded in the backup instead of by user/group name'
+								 --restore-atime      'Restore access times too (by default they are set to the modification time)'
 								 <NAME>               'Name of the backup to restore'
 								 [PATH]               'Where to extract the backup to'")
+							.arg(
+								Arg::with_name("include")
+									.long("include")
+									.takes_value(true)
+									.multiple(true)
+									.number_of_values(1)
+									.help("Only restore paths matching the given glob (and the directories containing them)")
+							)
+							.arg(
+								Arg::with_name("exclude")
+									.long("exclude")
+									.takes_value(true)
+									.multiple(true)
+									.number_of_values(1)
+									.help("Don't restore paths matching the given glob")
+							)
+							.arg(
+								Arg::with_name("on-conflict")
+									.long("on-conflict")
+									.takes_value(true)
+									.possible_values(&["error", "skip", "overwrite", "if-newer", "rename"])
+									.default_value("error")
+									.help("What to do when a path being restored already exists: stop with an error, skip it, overwrite it, overwrite it only if the backup's copy is newer, or rename the existing path to PATH.~N~ first")
+							)
+							.arg(
+								Arg::with_name("xattr-namespaces")
+									.long("xattr-namespaces")
+									.takes_value(true)
+									.value_name("NAMESPACES")
+									.default_value("security,system,trusted,user")
+									.help("Comma separated list of extended attribute namespaces to restore (security, system, trusted, user), or 'none'")
+							)
 						)
 						.subcommand(SubCommand::with_name("verify")
 							.about("verify the integrity of an existing backup and all encrypted blocks it references")
@@ -78,6 +183,15 @@ fn main() {
 								 --backend=<BACKEND>  'Sets the backend to use'
 								 <NAME>               'The name of the backup to verify'")
 						)
+						.subcommand(SubCommand::with_name("audit")
+							.about("check that no backup recorded in an archive log, or any block it references, has been removed from the backend")
+							.setting(AppSettings::UnifiedHelpMessage)
+							.setting(AppSettings::ColoredHelp)
+							.args_from_usage(
+								"--keyfile=<KEYFILE>      'Sets the keyfile to use'
+								 --backend=<BACKEND>      'Sets the backend to use'
+								 --archive-log=<FILE>     'The archive log written by create --archive-log'")
+						)
 						.subcommand(SubCommand::with_name("diff")
 							.about("diff two existing backups")
 							.setting(AppSettings::UnifiedHelpMessage)
@@ -88,7 +202,92 @@ fn main() {
 								 <NAME1>              'The name of the first backup'
 								 <NAME2>              'The name of the second backup'")
 						)
-						.get_matches();
+						.subcommand(SubCommand::with_name("ls")
+							.about("list the files inside an existing backup")
+							.setting(AppSettings::UnifiedHelpMessage)
+							.setting(AppSettings::ColoredHelp)
+							.args_from_usage(
+								"--keyfile=<KEYFILE>  'Sets the keyfile to use'
+								 --backend=<BACKEND>  'Sets the backend to use'
+								 -R, --recursive      'List everything under PATH, not just its direct children'
+								 --glob=[GLOB]        'Only list entries whose path matches GLOB'
+								 --json               'Output JSON'
+								 <NAME>               'The name of the backup'
+								 [PATH]               'The directory (or file) inside the backup to list'")
+						)
+						.subcommand(SubCommand::with_name("cat")
+							.about("write a single file from an existing backup to stdout")
+							.setting(AppSettings::UnifiedHelpMessage)
+							.setting(AppSettings::ColoredHelp)
+							.args_from_usage(
+								"--keyfile=<KEYFILE>  'Sets the keyfile to use'
+								 --backend=<BACKEND>  'Sets the backend to use'
+								 <NAME>               'The name of the backup'
+								 <PATH>               'The path of the file inside the backup'")
+						)
+						.subcommand(SubCommand::with_name("delete")
+							.about("delete an existing backup (run gc afterwards to free its blocks)")
+							.setting(AppSettings::UnifiedHelpMessage)
+							.setting(AppSettings::ColoredHelp)
+							.args_from_usage(
+								"--keyfile=<KEYFILE>  'Sets the keyfile to use'
+								 --backend=<BACKEND>  'Sets the backend to use'
+								 <NAME>               'The name of the backup to delete'")
+						)
+						.subcommand(SubCommand::with_name("gc")
+							.about("remove blocks that are not referenced by any backup (do not run while a backup is being created)")
+							.setting(AppSettings::UnifiedHelpMessage)
+							.setting(AppSettings::ColoredHelp)
+							.args_from_usage(
+								"--keyfile=<KEYFILE>  'Sets the keyfile to use'
+								 --backend=<BACKEND>  'Sets the backend to use'
+								 --dry-run            'Only report which blocks would be removed'")
+						)
+						.subcommand(SubCommand::with_name("prune")
+							.about("delete backups according to a retention policy (run gc afterwards to free their blocks)")
+							.setting(AppSettings::UnifiedHelpMessage)
+							.setting(AppSettings::ColoredHelp)
+							.args_from_usage(
+								"--keyfile=<KEYFILE>       'Sets the keyfile to use'
+								 --backend=<BACKEND>       'Sets the backend to use'
+								 --keep-last=[N]           'Keep the N newest backups'
+								 --keep-daily=[N]          'Keep the newest backup of each of the last N days that have backups'
+								 --keep-weekly=[N]         'Keep the newest backup of each of the last N weeks that have backups'
+								 --keep-monthly=[N]        'Keep the newest backup of each of the last N months that have backups'
+								 --dry-run                 'Only show what would be deleted'")
+						);
+
+	#[cfg(feature = "mount")]
+	let app = app.subcommand(SubCommand::with_name("mount")
+		.about("mount the backups as a read-only filesystem")
+		.setting(AppSettings::UnifiedHelpMessage)
+		.setting(AppSettings::ColoredHelp)
+		.args_from_usage(
+			"--keyfile=<KEYFILE>  'Sets the keyfile to use'
+			 --backend=<BACKEND>  'Sets the backend to use'
+			 <MOUNTPOINT>         'The directory to mount the backups at'")
+	);
+
+	#[cfg(feature = "http")]
+	let app = app.subcommand(SubCommand::with_name("serve")
+		.about("serve a file backend to http(s):// clients")
+		.setting(AppSettings::UnifiedHelpMessage)
+		.setting(AppSettings::ColoredHelp)
+		.args_from_usage(
+			"--root=<DIR>                'The directory to store backups in'
+			 --token-file=<TOKENFILE>    'File containing the token clients must send'
+			 --append-only               'Refuse to delete anything'")
+		.arg(
+			Arg::with_name("listen")
+				.long("listen")
+				.takes_value(true)
+				.value_name("ADDRESS")
+				.default_value("127.0.0.1:8200")
+				.help("Address and port to listen on")
+		)
+	);
+
+	let matches = app.get_matches();
 
 	Logger::init(log::LevelFilter::Info, matches.value_of("logfile"));
 
@@ -98,7 +297,17 @@ fn main() {
 		("list", Some(sub_m)) => cmds::list::execute(sub_m),
 		("restore", Some(sub_m)) => cmds::restore::execute(sub_m),
 		("verify", Some(sub_m)) => cmds::verify::execute(sub_m),
+		("audit", Some(sub_m)) => cmds::audit::execute(sub_m),
 		("diff", Some(sub_m)) => cmds::diff::execute(sub_m),
+		("ls", Some(sub_m)) => cmds::ls::execute(sub_m),
+		("cat", Some(sub_m)) => cmds::cat::execute(sub_m),
+		("delete", Some(sub_m)) => cmds::delete::execute(sub_m),
+		("gc", Some(sub_m)) => cmds::gc::execute(sub_m),
+		("prune", Some(sub_m)) => cmds::prune::execute(sub_m),
+		#[cfg(feature = "mount")]
+		("mount", Some(sub_m)) => cmds::mount::execute(sub_m),
+		#[cfg(feature = "http")]
+		("serve", Some(sub_m)) => cmds::serve::execute(sub_m),
 		_ => panic!("Unknown subcommand"),
 	}
 }
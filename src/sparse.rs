@@ -0,0 +1,271 @@
+use crate::archive::Hole;
+use std::cmp;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+
+
+/// Find the holes in the first size bytes of a file using SEEK_DATA and SEEK_HOLE.
+/// Filesystems which don't support those report no holes.
+pub fn find_holes(file: &fs::File, size: u64) -> io::Result<Vec<Hole>> {
+	let fd = file.as_raw_fd();
+	let mut holes = Vec::new();
+	let mut position = 0;
+
+	while position < size {
+		let data = unsafe { libc::lseek(fd, position as libc::off_t, libc::SEEK_DATA) };
+
+		if data < 0 {
+			let err = io::Error::last_os_error();
+
+			match err.raw_os_error() {
+				// No more data, so the rest of the file is a hole
+				Some(libc::ENXIO) => {
+					holes.push(Hole { offset: position, length: size - position });
+					break;
+				},
+				Some(libc::EINVAL) if position == 0 => return Ok(Vec::new()),
+				_ => return Err(err),
+			}
+		}
+
+		let data = cmp::min(data as u64, size);
+
+		if data > position {
+			holes.push(Hole { offset: position, length: data - position });
+		}
+
+		if data >= size {
+			break;
+		}
+
+		let hole = unsafe { libc::lseek(fd, data as libc::off_t, libc::SEEK_HOLE) };
+
+		if hole < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		position = hole as u64;
+	}
+
+	// Put the file offset back where reading expects it
+	if unsafe { libc::lseek(fd, 0, libc::SEEK_SET) } < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	Ok(holes)
+}
+
+
+/// The (offset, length) of the data regions in a file of the given size with the given (sorted) holes.
+pub fn data_extents(holes: &[Hole], size: u64) -> Vec<(u64, u64)> {
+	let mut extents = Vec::new();
+	let mut position = 0;
+
+	for hole in holes {
+		if hole.offset > position {
+			extents.push((position, hole.offset - position));
+		}
+
+		position = hole.offset + hole.length;
+	}
+
+	if size > position {
+		extents.push((position, size - position));
+	}
+
+	extents
+}
+
+
+/// Total number of bytes in holes.  The blocks of a file hold size minus this many bytes.
+pub fn hole_size(holes: &[Hole]) -> u64 {
+	holes.iter().map(|hole| hole.length).sum()
+}
+
+
+/// Reads only the data regions of a file, one after another, skipping the holes.
+pub struct DataReader<R> {
+	inner: R,
+	extents: Vec<(u64, u64)>,
+	index: usize,
+	/// Position within the current extent
+	position: u64,
+}
+
+impl<R: Read + Seek> DataReader<R> {
+	pub fn new(inner: R, holes: &[Hole], size: u64) -> DataReader<R> {
+		DataReader {
+			inner,
+			extents: data_extents(holes, size),
+			index: 0,
+			position: 0,
+		}
+	}
+}
+
+impl<R: Read + Seek> Read for DataReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while let Some(&(offset, length)) = self.extents.get(self.index) {
+			if self.position >= length {
+				self.index += 1;
+				self.position = 0;
+				continue;
+			}
+
+			if self.position == 0 {
+				self.inner.seek(SeekFrom::Start(offset))?;
+			}
+
+			let want = cmp::min(buf.len() as u64, length - self.position) as usize;
+			let read = self.inner.read(&mut buf[..want])?;
+
+			// If the file shrank, the caller will notice that it read less than expected
+			self.position += read as u64;
+			return Ok(read);
+		}
+
+		Ok(0)
+	}
+}
+
+
+/// The inverse of DataReader: data written to it is placed around the holes.
+/// How a hole is skipped depends on the underlying writer; files can seek over them, leaving a real hole,
+/// while streams have to be filled with zeros.
+pub struct DataWriter<W> {
+	inner: W,
+	holes: Vec<Hole>,
+	index: usize,
+	/// Logical position in the file
+	position: u64,
+	skip: fn(&mut W, u64) -> io::Result<()>,
+}
+
+impl<W: Write + Seek> DataWriter<W> {
+	/// Skips holes by seeking.  A hole at the end of the file needs a set_len to be created.
+	pub fn seeking(inner: W, holes: &[Hole]) -> DataWriter<W> {
+		DataWriter::new(inner, holes, |writer, length| writer.seek(SeekFrom::Current(length as i64)).map(|_| ()))
+	}
+}
+
+impl<W: Write> DataWriter<W> {
+	/// Skips holes by writing zeros.
+	pub fn zero_filling(inner: W, holes: &[Hole]) -> DataWriter<W> {
+		DataWriter::new(inner, holes, |writer, length| io::copy(&mut io::repeat(0).take(length), writer).map(|_| ()))
+	}
+
+	fn new(inner: W, holes: &[Hole], skip: fn(&mut W, u64) -> io::Result<()>) -> DataWriter<W> {
+		DataWriter {
+			inner,
+			holes: holes.to_vec(),
+			index: 0,
+			position: 0,
+			skip,
+		}
+	}
+
+	/// Skip any holes starting at the current position.
+	fn skip_holes(&mut self) -> io::Result<()> {
+		while let Some(hole) = self.holes.get(self.index).cloned() {
+			if hole.offset > self.position {
+				break;
+			}
+
+			(self.skip)(&mut self.inner, hole.length)?;
+			self.position = hole.offset + hole.length;
+			self.index += 1;
+		}
+
+		Ok(())
+	}
+
+	/// Skip any trailing holes and flush.  Returns the logical size written.
+	pub fn finish(mut self) -> io::Result<u64> {
+		self.skip_holes()?;
+		self.inner.flush()?;
+
+		Ok(self.position)
+	}
+}
+
+impl<W: Write> Write for DataWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.skip_holes()?;
+
+		let want = match self.holes.get(self.index) {
+			Some(hole) => cmp::min(buf.len() as u64, hole.offset - self.position) as usize,
+			None => buf.len(),
+		};
+		let written = self.inner.write(&buf[..want])?;
+
+		self.position += written as u64;
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+
+#[cfg(test)]
+mod test {
+	use super::{data_extents, find_holes, DataReader, DataWriter};
+	use crate::archive::Hole;
+	use std::fs;
+	use std::io::{Read, Seek, SeekFrom, Write};
+
+	#[test]
+	fn test_data_extents() {
+		let holes = [Hole { offset: 0, length: 10 }, Hole { offset: 20, length: 5 }];
+
+		assert_eq!(data_extents(&holes, 30), vec![(10, 10), (25, 5)]);
+		assert_eq!(data_extents(&holes, 25), vec![(10, 10)]);
+		assert_eq!(data_extents(&[], 30), vec![(0, 30)]);
+		assert_eq!(data_extents(&[], 0), vec![]);
+	}
+
+	#[test]
+	fn test_data_reader_writer_round_trip() {
+		let holes = [Hole { offset: 2, length: 3 }, Hole { offset: 8, length: 4 }];
+		let logical = b"ab\0\0\0cde\0\0\0\0";
+
+		let mut data = Vec::new();
+		DataReader::new(std::io::Cursor::new(&logical[..]), &holes, logical.len() as u64).read_to_end(&mut data).unwrap();
+		assert_eq!(data, b"abcde");
+
+		let mut output = Vec::new();
+		let mut writer = DataWriter::zero_filling(&mut output, &holes);
+		writer.write_all(&data).unwrap();
+		assert_eq!(writer.finish().unwrap(), logical.len() as u64);
+		assert_eq!(&output[..], &logical[..]);
+	}
+
+	#[test]
+	fn test_sparse_file() {
+		let dir = tempfile::Builder::new().prefix("preserve-test-").tempdir().unwrap();
+		let path = dir.path().join("sparse");
+		let size = 8 * 1024 * 1024;
+		let mut file = fs::File::create(&path).unwrap();
+		file.seek(SeekFrom::Start(4 * 1024 * 1024)).unwrap();
+		file.write_all(b"data").unwrap();
+		file.set_len(size).unwrap();
+		let file = fs::File::open(&path).unwrap();
+
+		let holes = find_holes(&file, size).unwrap();
+
+		// Filesystems don't have to support holes, in which case there's nothing to check
+		if holes.is_empty() {
+			return;
+		}
+
+		assert_eq!(holes.first().unwrap().offset, 0);
+		assert_eq!(holes.last().unwrap().offset + holes.last().unwrap().length, size);
+
+		let mut data = Vec::new();
+		DataReader::new(&file, &holes, size).read_to_end(&mut data).unwrap();
+		assert!(data.len() < 1024 * 1024);
+		assert!(data.windows(4).any(|window| window == b"data"));
+	}
+}
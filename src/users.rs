@@ -0,0 +1,73 @@
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::ptr;
+
+
+// Large enough for any sane passwd/group entry.
+const LOOKUP_BUFFER_SIZE: usize = 16 * 1024;
+
+
+/// Look up the name of the user with the given uid in the local user database.
+pub fn user_name(uid: u32) -> Option<String> {
+	let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+	let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+	let mut result = ptr::null_mut();
+
+	let ret = unsafe { libc::getpwuid_r(uid, &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+
+	if ret != 0 || result.is_null() {
+		return None;
+	}
+
+	unsafe { CStr::from_ptr(passwd.pw_name) }.to_str().ok().map(|name| name.to_owned())
+}
+
+
+/// Look up the name of the group with the given gid in the local group database.
+pub fn group_name(gid: u32) -> Option<String> {
+	let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+	let mut group: libc::group = unsafe { mem::zeroed() };
+	let mut result = ptr::null_mut();
+
+	let ret = unsafe { libc::getgrgid_r(gid, &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+
+	if ret != 0 || result.is_null() {
+		return None;
+	}
+
+	unsafe { CStr::from_ptr(group.gr_name) }.to_str().ok().map(|name| name.to_owned())
+}
+
+
+/// Look up the uid of the user with the given name in the local user database.
+pub fn user_id(name: &str) -> Option<u32> {
+	let name = CString::new(name).ok()?;
+	let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+	let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+	let mut result = ptr::null_mut();
+
+	let ret = unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+
+	if ret != 0 || result.is_null() {
+		return None;
+	}
+
+	Some(passwd.pw_uid)
+}
+
+
+/// Look up the gid of the group with the given name in the local group database.
+pub fn group_id(name: &str) -> Option<u32> {
+	let name = CString::new(name).ok()?;
+	let mut buffer = vec![0 as libc::c_char; LOOKUP_BUFFER_SIZE];
+	let mut group: libc::group = unsafe { mem::zeroed() };
+	let mut result = ptr::null_mut();
+
+	let ret = unsafe { libc::getgrnam_r(name.as_ptr(), &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+
+	if ret != 0 || result.is_null() {
+		return None;
+	}
+
+	Some(group.gr_gid)
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::Path;
+
+
+/// Every extended attribute namespace Linux knows about.
+/// `system` holds POSIX ACLs, `security` holds file capabilities and SELinux labels.
+pub const NAMESPACES: [&str; 4] = ["security", "system", "trusted", "user"];
+
+/// The namespaces backed up by default.  `trusted` is left out since only root can read it.
+pub const DEFAULT_NAMESPACES: &str = "security,system,user";
+
+
+/// Parse a comma separated list of namespaces.  "none" (or an empty string) means no namespaces.
+pub fn parse_namespaces(s: &str) -> Option<Vec<String>> {
+	if s == "none" {
+		return Some(Vec::new());
+	}
+
+	s.split(',').filter(|namespace| !namespace.is_empty()).map(|namespace| {
+		if NAMESPACES.contains(&namespace) {
+			Some(namespace.to_owned())
+		} else {
+			None
+		}
+	}).collect()
+}
+
+
+/// Read all the extended attributes of path that belong to one of the given namespaces.
+/// If follow_symlinks is false and path is a symlink, the symlink's own attributes are read.
+/// Filesystems which don't support extended attributes simply have none.
+pub fn read(path: &Path, follow_symlinks: bool, namespaces: &[String]) -> io::Result<BTreeMap<String, Vec<u8>>> {
+	let mut xattrs = BTreeMap::new();
+
+	if namespaces.is_empty() {
+		return Ok(xattrs);
+	}
+
+	let p = CString::new(path.as_os_str().as_bytes()).expect("internal error");
+
+	let names = match read_buffer(|buffer, size| unsafe {
+		if follow_symlinks {
+			libc::listxattr(p.as_ptr(), buffer as *mut libc::c_char, size)
+		} else {
+			libc::llistxattr(p.as_ptr(), buffer as *mut libc::c_char, size)
+		}
+	}) {
+		Ok(names) => names,
+		Err(ref err) if err.raw_os_error() == Some(libc::ENOTSUP) => return Ok(xattrs),
+		Err(err) => return Err(err),
+	};
+
+	// The list is a sequence of NUL terminated names
+	for name in names.split(|c| *c == 0).filter(|name| !name.is_empty()) {
+		let name = match String::from_utf8(name.to_vec()) {
+			Ok(name) => name,
+			Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "extended attribute name is not valid UTF-8")),
+		};
+
+		if !namespaces.iter().any(|namespace| name.split('.').next() == Some(namespace)) {
+			continue;
+		}
+
+		let c_name = CString::new(name.as_bytes()).expect("internal error");
+
+		let value = match read_buffer(|buffer, size| unsafe {
+			if follow_symlinks {
+				libc::getxattr(p.as_ptr(), c_name.as_ptr(), buffer, size)
+			} else {
+				libc::lgetxattr(p.as_ptr(), c_name.as_ptr(), buffer, size)
+			}
+		}) {
+			Ok(value) => value,
+			// Removed between listing and reading
+			Err(ref err) if err.raw_os_error() == Some(libc::ENODATA) => continue,
+			Err(err) => return Err(err),
+		};
+
+		xattrs.insert(name, value);
+	}
+
+	Ok(xattrs)
+}
+
+
+/// Set an extended attribute on path, without following symlinks.
+pub fn write(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+	let p = CString::new(path.as_os_str().as_bytes()).expect("internal error");
+	let c_name = CString::new(name.as_bytes()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "extended attribute name contains a NUL byte"))?;
+
+	let ret = unsafe { libc::lsetxattr(p.as_ptr(), c_name.as_ptr() as *const libc::c_char, value.as_ptr() as *const libc::c_void, value.len(), 0) };
+
+	if ret == 0 {
+		Ok(())
+	} else {
+		Err(io::Error::last_os_error())
+	}
+}
+
+
+/// The *xattr calls return the required buffer size when given a zero sized buffer, but the size can change
+/// between calls, so retry on ERANGE.
+fn read_buffer<F: Fn(*mut libc::c_void, usize) -> libc::ssize_t>(f: F) -> io::Result<Vec<u8>> {
+	loop {
+		let size = f(std::ptr::null_mut(), 0);
+
+		if size < 0 {
+			return Err(io::Error::last_os_error());
+		}
+
+		let mut buffer = vec![0u8; size as usize];
+		let size = f(buffer.as_mut_ptr() as *mut libc::c_void, buffer.len());
+
+		if size >= 0 {
+			buffer.truncate(size as usize);
+			return Ok(buffer);
+		}
+
+		let err = io::Error::last_os_error();
+
+		if err.raw_os_error() != Some(libc::ERANGE) {
+			return Err(err);
+		}
+	}
+}
+
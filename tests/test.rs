@@ -3,7 +3,7 @@ extern crate rand;
 extern crate libc;
 
 use rand::prelude::*;
-use std::process::Command;
+use std::process::{Command, Output};
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 use std::io::{Write, BufWriter};
@@ -72,6 +72,84 @@ fn integration_test_1() {
 		};
 	}
 
+	// Add a special file
+	make_fifo(original_dir.path().join("test.fifo"));
+
+	// Test again
+	{
+		test_config.create("test4", original_dir.path());
+		let restore_dir = test_config.restore("test4");
+		match compare_dirs(original_dir.path(), restore_dir.path()) {
+			Ok(_) => (),
+			Err(err) => handle_failed_restore(original_dir.path(), restore_dir.path(), "Restored directory did not match original directory with special file", &err),
+		};
+	}
+
+	// List the backups
+	{
+		let output = test_config.run("list", &[]);
+		assert!(output.status.success());
+		assert_eq!(String::from_utf8_lossy(&output.stdout), "test1\ntest2\ntest3\ntest4\n");
+	}
+
+	// List the files in a backup
+	{
+		let output = test_config.run("ls", &["test3"]);
+		assert!(output.status.success());
+		let stdout = String::from_utf8_lossy(&output.stdout);
+		let line = stdout.lines().find(|line| line.ends_with(" test.txt")).expect("ls did not list test.txt");
+		assert_eq!(line.split_whitespace().nth(3), Some("24"));
+
+		let output = test_config.run("ls", &["test4", "test.fifo"]);
+		assert!(output.status.success());
+		assert!(String::from_utf8_lossy(&output.stdout).starts_with('p'));
+	}
+
+	// Read single files back
+	{
+		let output = test_config.run("cat", &["test3", "test.txt"]);
+		assert!(output.status.success());
+		assert_eq!(output.stdout, b"This is a different file");
+
+		let output = test_config.run("cat", &["test2", "test.txt"]);
+		assert!(output.status.success());
+		assert_eq!(output.stdout, b"This is a new file");
+
+		assert!(!test_config.run("cat", &["test1", "test.txt"]).status.success());
+	}
+
+	// Browse the backups through a FUSE mount
+	#[cfg(feature = "mount")]
+	{
+		let mountpoint = tempfile::Builder::new().prefix("preserve-test").tempdir().unwrap();
+		let mut child = test_config.command("mount").arg(mountpoint.path()).spawn().unwrap();
+		let file_path = mountpoint.path().join("test3").join("test.txt");
+
+		// The mount is ready once the file can be read
+		let mut contents = None;
+		for _ in 0..100 {
+			if let Some(status) = child.try_wait().unwrap() {
+				panic!("mount exited early: {}", status);
+			}
+
+			if let Ok(data) = fs::read(&file_path) {
+				contents = Some(data);
+				break;
+			}
+
+			std::thread::sleep(std::time::Duration::from_millis(100));
+		}
+
+		let mut names: Vec<String> = fs::read_dir(mountpoint.path()).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+		names.sort();
+
+		Command::new("fusermount").arg("-u").arg(mountpoint.path()).status().unwrap();
+		child.wait().unwrap();
+
+		assert_eq!(contents.expect("the mount never became readable"), b"This is a different file");
+		assert_eq!(names, ["test1", "test2", "test3", "test4"]);
+	}
+
 	// Check old backup
 	let original_dir = TestGenerator::new().generate_test_case();
 
@@ -91,6 +169,18 @@ fn integration_test_1() {
 			Err(_) => (),
 		};
 	}
+
+	// Audit the backups recorded in an archive log, before and after one is removed
+	{
+		let output = test_config.run("create", &["--archive-log", "archive.log", "test5", original_dir.path().to_str().unwrap()]);
+		assert!(output.status.success());
+		assert!(test_config.run("audit", &["--archive-log", "archive.log"]).status.success());
+
+		assert!(test_config.run("delete", &["test5"]).status.success());
+		let output = test_config.run("audit", &["--archive-log", "archive.log"]);
+		assert!(!output.status.success());
+		assert!(String::from_utf8_lossy(&output.stdout).contains("MISSING: The backup 'test5'"));
+	}
 }
 
 
@@ -154,6 +244,26 @@ impl TestConfig {
 
 		restore_dir
 	}
+
+	// A command running one of the other subcommands against the backend
+	pub fn command(&self, subcommand: &str) -> Command {
+		let mut command = Command::new(&self.bin);
+		command.current_dir(&self.working_dir)
+			.arg(subcommand)
+			.arg("--keyfile").arg("keyfile")
+			.arg("--backend").arg("file://".to_string() + &self.backend_dir.to_string_lossy());
+
+		command
+	}
+
+	pub fn run(&self, subcommand: &str, args: &[&str]) -> Output {
+		let output = self.command(subcommand).args(args).output().unwrap();
+
+		println!("{}-stdout: {}", subcommand, String::from_utf8_lossy(&output.stdout));
+		println!("{}-stderr: {}", subcommand, String::from_utf8_lossy(&output.stderr));
+
+		output
+	}
 }
 
 
@@ -424,6 +534,17 @@ impl TestGenerator {
 	}
 }
 
+fn make_fifo<P: AsRef<Path>>(path: P) {
+	use std::ffi::CString;
+	use std::os::unix::prelude::*;
+
+	let p = CString::new(path.as_ref().as_os_str().as_bytes()).unwrap();
+
+	if unsafe { libc::mkfifo(p.as_ptr(), 0o644) } != 0 {
+		panic!("mkfifo failed: {}", std::io::Error::last_os_error());
+	}
+}
+
 // Compares the given directories using rsync.
 // The returned error String is the output of rsync when they don't match.
 fn compare_dirs<P: AsRef<Path>, Q: AsRef<Path>>(path1: P, path2: Q) -> Result<(), String> {